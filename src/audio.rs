@@ -0,0 +1,388 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Instant;
+use super::tags;
+#[cfg(feature = "fmod")]
+use super::fmod_backend::FmodBackend;
+
+// the null backend can't decode anything, so streams and untagged files pretend to be this long
+const NULL_UNKNOWN_LENGTH_MS: u32 = 3 * 60 * 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SoundId(pub usize);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ChannelId(pub usize);
+
+/// Layout of decoded audio; samples are interleaved floats in -1.0..1.0.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PcmFormat {
+    pub rate: u32,
+    pub channels: usize,
+}
+
+/// Everything `MediaPlayer` needs from an audio library. Sounds are loaded once and can be
+/// played on any number of channels; all positions and lengths are in milliseconds.
+pub trait AudioBackend {
+    fn load(&mut self, uri: &str) -> Result<SoundId, failure::Error>;
+    fn release(&mut self, sound: SoundId);
+    fn play(&mut self, sound: SoundId) -> Result<ChannelId, failure::Error>;
+    /// Starts `sound` on the exact sample `after` runs out, less `overlap_ms` of `after` for crossfades. Pausing,
+    /// seeking or changing the rate of `after` doesn't move the start, the caller has to stop the channel and schedule again.
+    fn play_after(&mut self, sound: SoundId, after: ChannelId, overlap_ms: u32) -> Result<ChannelId, failure::Error>;
+    fn stop(&mut self, channel: ChannelId);
+    fn set_paused(&mut self, channel: ChannelId, paused: bool);
+    fn is_paused(&self, channel: ChannelId) -> bool;
+    /// False once the channel has reached the end of its sound or been stopped.
+    fn is_playing(&self, channel: ChannelId) -> bool;
+    fn seek(&mut self, channel: ChannelId, position_ms: u32);
+    /// Linear gain, 1.0 is the file's own level.
+    fn set_volume(&mut self, channel: ChannelId, volume: f32);
+    fn volume(&self, channel: ChannelId) -> f32;
+    /// Playback speed, 1.0 is normal. Pitch is kept where it was.
+    fn set_rate(&mut self, channel: ChannelId, rate: f32);
+    fn rate(&self, channel: ChannelId) -> f32;
+    fn position(&self, channel: ChannelId) -> Option<u32>;
+    fn length(&self, sound: SoundId) -> Option<u32>;
+    fn waveform(&self, channel: ChannelId, samples: usize) -> Vec<f32>;
+    fn spectrum(&self, channel: ChannelId, bands: usize) -> Vec<f32>;
+    /// Milliseconds since the backend started, running whether or not anything plays.
+    fn now_ms(&self) -> u64;
+    /// Decodes the whole of `uri` without playing it, handing samples to `sink` a chunk at a time.
+    fn decode(&mut self, uri: &str, sink: &mut dyn FnMut(PcmFormat, &[f32])) -> Result<(), failure::Error>;
+    /// Called once per tick for backends that need pumping.
+    fn update(&mut self) {}
+}
+
+/// Picks a backend by the config's `audio_backend` name. An empty name means FMOD when it was compiled in.
+pub fn create_backend(name: &str) -> Result<Box<dyn AudioBackend>, failure::Error> {
+    match name {
+        "null" => Ok(Box::new(NullBackend::new())),
+        #[cfg(feature = "fmod")]
+        "" | "fmod" => Ok(Box::new(FmodBackend::new()?)),
+        #[cfg(not(feature = "fmod"))]
+        "" => Ok(Box::new(NullBackend::new())),
+        other => Err(failure::err_msg(format!("unknown or unavailable audio backend \"{}\"", other)))
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time, so a test can keep one
+/// and hand another to a `NullBackend`.
+#[derive(Clone)]
+pub struct ManualClock {
+    now_ms: Rc<Cell<u64>>,
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock { now_ms: Rc::new(Cell::new(0)) }
+    }
+
+    pub fn advance(&self, ms: u64) {
+        self.now_ms.set(self.now_ms.get() + ms);
+    }
+
+    pub fn now_ms(&self) -> u64 {
+        self.now_ms.get()
+    }
+}
+
+/// Fixtures shared by the playback tests.
+#[cfg(test)]
+pub mod testing {
+    use super::{ ManualClock, NullBackend };
+    use super::super::Config;
+    use super::super::player::{ MediaPlayer, Song };
+
+    /// A song named after its path, with everything else left at defaults.
+    pub fn song(path: &str) -> Song {
+        Song { name: String::from(path), path: String::from(path), ..Song::default() }
+    }
+
+    /// A player on a `NullBackend` that only moves with the returned clock, and takes the
+    /// files in `lengths` to be that many milliseconds long.
+    pub fn media_player(config: Config, lengths: &[(&str, u32)]) -> (MediaPlayer, ManualClock) {
+        let clock = ManualClock::new();
+        let mut backend = NullBackend::with_clock(clock.clone());
+        for &(uri, length) in lengths { backend.set_length(uri, length); }
+        (MediaPlayer::with_backend(config, Box::new(backend)), clock)
+    }
+}
+
+enum Clock {
+    Wall(Instant),
+    Manual(ManualClock),
+}
+
+struct NullChannel {
+    sound: SoundId,
+    // position when the channel was last paused, seeked or started, and the clock time it happened
+    base_position: u32,
+    base_time: u64,
+    paused: bool,
+    stopped: bool,
+    volume: f32,
+    rate: f32,
+}
+
+/// Silent backend that only keeps time, for machines without FMOD and for tests. Lengths come
+/// from `set_length`, then tags, then `NULL_UNKNOWN_LENGTH_MS`.
+pub struct NullBackend {
+    clock: Clock,
+    lengths: HashMap<String, u32>,
+    pcm: HashMap<String, (PcmFormat, Vec<f32>)>,
+    sounds: HashMap<usize, u32>,
+    channels: HashMap<usize, NullChannel>,
+    next_id: usize,
+}
+
+impl NullBackend {
+    pub fn new() -> NullBackend {
+        NullBackend::with_clock_source(Clock::Wall(Instant::now()))
+    }
+
+    /// A backend whose channels only advance when `clock` does.
+    pub fn with_clock(clock: ManualClock) -> NullBackend {
+        NullBackend::with_clock_source(Clock::Manual(clock))
+    }
+
+    fn with_clock_source(clock: Clock) -> NullBackend {
+        NullBackend {
+            clock: clock,
+            lengths: HashMap::new(),
+            pcm: HashMap::new(),
+            sounds: HashMap::new(),
+            channels: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Pretends `uri` is `length_ms` long when it's loaded, instead of reading its tags.
+    pub fn set_length(&mut self, uri: &str, length_ms: u32) {
+        self.lengths.insert(String::from(uri), length_ms);
+    }
+
+    /// What `decode` hands back for `uri`; anything else fails to decode.
+    pub fn set_pcm(&mut self, uri: &str, format: PcmFormat, samples: Vec<f32>) {
+        self.pcm.insert(String::from(uri), (format, samples));
+    }
+
+    fn next_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn channel_position(&self, channel: &NullChannel) -> u32 {
+        let length = self.sounds.get(&channel.sound.0).cloned().unwrap_or(0);
+        // base_time can be in the future for channels scheduled with play_after
+        let position = if channel.paused || channel.stopped {
+            channel.base_position as u64
+        } else {
+            channel.base_position as u64 + (self.now_ms().saturating_sub(channel.base_time) as f64 * channel.rate as f64) as u64
+        };
+        if position > length as u64 { length } else { position as u32 }
+    }
+}
+
+impl AudioBackend for NullBackend {
+    fn load(&mut self, uri: &str) -> Result<SoundId, failure::Error> {
+        let length = match self.lengths.get(uri) {
+            Some(&length) => length,
+            None => match tags::read_tags(Path::new(uri)) {
+                Ok(tags) => tags.length.unwrap_or(NULL_UNKNOWN_LENGTH_MS),
+                Err(_) => NULL_UNKNOWN_LENGTH_MS
+            }
+        };
+        let id = self.next_id();
+        self.sounds.insert(id, length);
+        Ok(SoundId(id))
+    }
+
+    fn release(&mut self, sound: SoundId) {
+        self.sounds.remove(&sound.0);
+        self.channels.retain(|_, c| c.sound != sound);
+    }
+
+    fn play(&mut self, sound: SoundId) -> Result<ChannelId, failure::Error> {
+        if !self.sounds.contains_key(&sound.0) {
+            return Err(failure::err_msg("sound was released"));
+        }
+        let id = self.next_id();
+        let now = self.now_ms();
+        self.channels.insert(id, NullChannel { sound: sound, base_position: 0, base_time: now, paused: false, stopped: false, volume: 1.0, rate: 1.0 });
+        Ok(ChannelId(id))
+    }
+
+    fn play_after(&mut self, sound: SoundId, after: ChannelId, overlap_ms: u32) -> Result<ChannelId, failure::Error> {
+        let remaining = match self.channels.get(&after.0) {
+            Some(c) if !c.stopped => {
+                let length = self.sounds.get(&c.sound.0).cloned().unwrap_or(0);
+                ((length - self.channel_position(c)).saturating_sub(overlap_ms) as f64 / c.rate as f64) as u64
+            }
+            _ => 0
+        };
+        let channel = self.play(sound)?;
+        if let Some(c) = self.channels.get_mut(&channel.0) { c.base_time += remaining; }
+        Ok(channel)
+    }
+
+    fn stop(&mut self, channel: ChannelId) {
+        let position = self.channels.get(&channel.0).map(|c| self.channel_position(c));
+        if let (Some(c), Some(position)) = (self.channels.get_mut(&channel.0), position) {
+            c.base_position = position;
+            c.stopped = true;
+        }
+    }
+
+    fn set_paused(&mut self, channel: ChannelId, paused: bool) {
+        let position = self.channels.get(&channel.0).map(|c| self.channel_position(c));
+        let now = self.now_ms();
+        if let (Some(c), Some(position)) = (self.channels.get_mut(&channel.0), position) {
+            c.base_position = position;
+            c.base_time = now;
+            c.paused = paused;
+        }
+    }
+
+    fn is_paused(&self, channel: ChannelId) -> bool {
+        self.channels.get(&channel.0).map_or(false, |c| c.paused)
+    }
+
+    fn is_playing(&self, channel: ChannelId) -> bool {
+        match self.channels.get(&channel.0) {
+            Some(c) => !c.stopped && Some(self.channel_position(c)) < self.sounds.get(&c.sound.0).cloned(),
+            None => false
+        }
+    }
+
+    fn seek(&mut self, channel: ChannelId, position_ms: u32) {
+        let now = self.now_ms();
+        if let Some(c) = self.channels.get_mut(&channel.0) {
+            c.base_position = position_ms;
+            c.base_time = now;
+        }
+    }
+
+    fn position(&self, channel: ChannelId) -> Option<u32> {
+        self.channels.get(&channel.0).map(|c| self.channel_position(c))
+    }
+
+    fn set_volume(&mut self, channel: ChannelId, volume: f32) {
+        if let Some(c) = self.channels.get_mut(&channel.0) { c.volume = volume; }
+    }
+
+    fn volume(&self, channel: ChannelId) -> f32 {
+        self.channels.get(&channel.0).map_or(0.0, |c| c.volume)
+    }
+
+    fn set_rate(&mut self, channel: ChannelId, rate: f32) {
+        let position = self.channels.get(&channel.0).map(|c| self.channel_position(c));
+        let now = self.now_ms();
+        if let (Some(c), Some(position)) = (self.channels.get_mut(&channel.0), position) {
+            // a channel waiting on play_after keeps its start time
+            if c.base_time < now {
+                c.base_position = position;
+                c.base_time = now;
+            }
+            c.rate = rate;
+        }
+    }
+
+    fn rate(&self, channel: ChannelId) -> f32 {
+        self.channels.get(&channel.0).map_or(1.0, |c| c.rate)
+    }
+
+    fn length(&self, sound: SoundId) -> Option<u32> {
+        self.sounds.get(&sound.0).cloned()
+    }
+
+    fn waveform(&self, _channel: ChannelId, samples: usize) -> Vec<f32> {
+        vec![0.0; samples]
+    }
+
+    fn spectrum(&self, _channel: ChannelId, bands: usize) -> Vec<f32> {
+        vec![0.0; bands]
+    }
+
+    fn now_ms(&self) -> u64 {
+        match &self.clock {
+            Clock::Wall(started) => {
+                let elapsed = started.elapsed();
+                elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64
+            }
+            Clock::Manual(clock) => clock.now_ms()
+        }
+    }
+
+    fn decode(&mut self, uri: &str, sink: &mut dyn FnMut(PcmFormat, &[f32])) -> Result<(), failure::Error> {
+        match self.pcm.get(uri) {
+            Some((format, samples)) => {
+                sink(*format, samples);
+                Ok(())
+            }
+            None => Err(failure::err_msg(format!("the null backend can't decode {}", uri)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn null_channel_follows_manual_clock() {
+        let clock = ManualClock::new();
+        let mut backend = NullBackend::with_clock(clock.clone());
+        backend.set_length("a.mp3", 5000);
+        let sound = backend.load("a.mp3").unwrap();
+        let channel = backend.play(sound).unwrap();
+        assert_eq!(backend.length(sound), Some(5000));
+        assert_eq!(backend.position(channel), Some(0));
+
+        clock.advance(1500);
+        assert_eq!(backend.position(channel), Some(1500));
+
+        backend.set_paused(channel, true);
+        clock.advance(1000);
+        assert_eq!(backend.position(channel), Some(1500));
+        backend.set_paused(channel, false);
+
+        backend.seek(channel, 4000);
+        clock.advance(250);
+        assert_eq!(backend.position(channel), Some(4250));
+        assert!(backend.is_playing(channel));
+
+        clock.advance(10000);
+        assert_eq!(backend.position(channel), Some(5000));
+        assert!(!backend.is_playing(channel));
+    }
+
+    #[test]
+    fn null_channel_rate_scales_time() {
+        let clock = ManualClock::new();
+        let mut backend = NullBackend::with_clock(clock.clone());
+        backend.set_length("a.mp3", 10000);
+        backend.set_length("b.mp3", 10000);
+        let a = backend.load("a.mp3").unwrap();
+        let channel = backend.play(a).unwrap();
+        clock.advance(1000);
+        backend.set_rate(channel, 2.0);
+        clock.advance(1000);
+        assert_eq!(backend.position(channel), Some(3000));
+
+        // 7 seconds of track left at double speed is 3.5 seconds of real time
+        let b = backend.load("b.mp3").unwrap();
+        let next = backend.play_after(b, channel, 0).unwrap();
+        clock.advance(3500);
+        assert!(!backend.is_playing(channel));
+        assert_eq!(backend.position(next), Some(0));
+    }
+
+    #[test]
+    fn unknown_backend_is_an_error() {
+        assert!(create_backend("null").is_ok());
+        assert!(create_backend("alsa").is_err());
+    }
+}
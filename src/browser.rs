@@ -0,0 +1,212 @@
+use tui::backend::Backend;
+use tui::widgets::{ Widget, Block, Borders, SelectableList };
+use tui::style::{ Color, Modifier, Style};
+use tui::layout::Rect;
+use tui::terminal::Frame;
+use super::player::Song;
+use super::search::Search;
+
+const CATEGORIES: [&str; 5] = ["Artists", "Albums", "Genres", "Years", "All Tracks"];
+
+/// Which songs a track listing shows. `None` matches anything, `Some("")` matches songs missing that tag.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrackFilter {
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<u32>,
+}
+
+impl TrackFilter {
+    fn matches(&self, song: &Song) -> bool {
+        self.artist.as_ref().map_or(true, |a| *a == song.artist)
+            && self.album.as_ref().map_or(true, |a| *a == song.album)
+            && self.genre.as_ref().map_or(true, |g| *g == song.genre)
+            && self.year.map_or(true, |y| y == song.year)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum View {
+    Categories,
+    Artists,
+    Albums(Option<String>),
+    Genres,
+    Years,
+    Tracks(TrackFilter),
+}
+
+/// Artists -> Albums -> Tracks (plus Genres and Years) drill down over the scanned library.
+pub struct Browser {
+    view: View,
+    history: Vec<(View, usize)>,
+    keys: Vec<String>,
+    tracks: Vec<usize>,
+    pub items: Vec<String>,
+    pub selected: usize,
+}
+
+fn label(key: &str, unknown: &str) -> String {
+    if key.is_empty() { String::from(unknown) } else { String::from(key) }
+}
+
+// unique values sorted case insensitively, with untagged songs collected under ""
+fn distinct<F>(songs: &[Song], filter: &TrackFilter, key: F) -> Vec<String> where F: Fn(&Song) -> String {
+    let mut keys: Vec<String> = songs.iter().filter(|s| filter.matches(s)).map(key).collect();
+    keys.sort_by(|a, b| (a.to_lowercase(), a).cmp(&(b.to_lowercase(), b)));
+    keys.dedup();
+    keys
+}
+
+impl Browser {
+    pub fn new() -> Browser {
+        Browser {
+            view: View::Categories,
+            history: Vec::new(),
+            keys: Vec::new(),
+            tracks: Vec::new(),
+            items: CATEGORIES.iter().map(|c| String::from(*c)).collect(),
+            selected: 0,
+        }
+    }
+
+    pub fn reset(&mut self, songs: &[Song]) {
+        self.view = View::Categories;
+        self.history.clear();
+        self.selected = 0;
+        self.refresh(songs);
+    }
+
+    pub fn refresh(&mut self, songs: &[Song]) {
+        self.tracks.clear();
+        self.keys = match &self.view {
+            View::Categories => CATEGORIES.iter().map(|c| String::from(*c)).collect(),
+            View::Artists => distinct(songs, &TrackFilter::default(), |s| s.artist.clone()),
+            View::Albums(artist) => distinct(songs, &TrackFilter { artist: artist.clone(), ..TrackFilter::default() }, |s| s.album.clone()),
+            View::Genres => distinct(songs, &TrackFilter::default(), |s| s.genre.clone()),
+            View::Years => {
+                let mut years: Vec<u32> = songs.iter().map(|s| s.year).collect();
+                years.sort();
+                years.dedup();
+                years.iter().map(|y| if *y == 0 { String::new() } else { y.to_string() }).collect()
+            }
+            View::Tracks(filter) => {
+                let mut tracks: Vec<usize> = (0..songs.len()).filter(|&i| filter.matches(&songs[i])).collect();
+                tracks.sort_by(|&a, &b| {
+                    let (a, b) = (&songs[a], &songs[b]);
+                    (a.artist.to_lowercase(), a.album.to_lowercase(), a.track, &a.path).cmp(&(b.artist.to_lowercase(), b.album.to_lowercase(), b.track, &b.path))
+                });
+                self.tracks = tracks;
+                Vec::new()
+            }
+        };
+
+        self.items = match &self.view {
+            View::Categories => self.keys.clone(),
+            View::Artists => self.keys.iter().map(|k| label(k, "Unknown Artist")).collect(),
+            View::Albums(_) => self.keys.iter().map(|k| label(k, "Unknown Album")).collect(),
+            View::Genres => self.keys.iter().map(|k| label(k, "Unknown Genre")).collect(),
+            View::Years => self.keys.iter().map(|k| label(k, "Unknown Year")).collect(),
+            View::Tracks(filter) => self.tracks.iter().map(|&i| {
+                let song = &songs[i];
+                if filter.artist.is_some() { song.name.clone() } else { format!("{} - {}", label(&song.artist, "Unknown Artist"), song.name) }
+            }).collect(),
+        };
+
+        if self.selected >= self.items.len() {
+            self.selected = if self.items.is_empty() { 0 } else { self.items.len() - 1 };
+        }
+    }
+
+    pub fn next(&mut self) {
+        if !self.items.is_empty() {
+            self.selected = if self.selected >= self.items.len() - 1 { 0 } else { self.selected + 1 };
+        }
+    }
+
+    pub fn previous(&mut self) {
+        if !self.items.is_empty() {
+            self.selected = if self.selected > 0 { self.selected - 1 } else { self.items.len() - 1 };
+        }
+    }
+
+    /// Library index of the highlighted song when a track listing is shown.
+    pub fn selected_track(&self) -> Option<usize> {
+        match self.view {
+            View::Tracks(_) => self.tracks.get(self.selected).cloned(),
+            _ => None
+        }
+    }
+
+    /// Drills into the selected entry. On a track listing this instead hands back the
+    /// listed song indices and the position that was picked.
+    pub fn enter(&mut self, songs: &[Song]) -> Option<(Vec<usize>, usize)> {
+        if self.items.is_empty() { return None; }
+        let key = self.keys.get(self.selected).cloned().unwrap_or_default();
+        let next_view = match &self.view {
+            View::Categories => match self.selected {
+                0 => View::Artists,
+                1 => View::Albums(None),
+                2 => View::Genres,
+                3 => View::Years,
+                _ => View::Tracks(TrackFilter::default())
+            },
+            View::Artists => View::Albums(Some(key)),
+            View::Albums(artist) => View::Tracks(TrackFilter { artist: artist.clone(), album: Some(key), ..TrackFilter::default() }),
+            View::Genres => View::Tracks(TrackFilter { genre: Some(key), ..TrackFilter::default() }),
+            View::Years => View::Tracks(TrackFilter { year: Some(key.parse().unwrap_or(0)), ..TrackFilter::default() }),
+            View::Tracks(_) => return Some((self.tracks.clone(), self.selected)),
+        };
+
+        self.history.push((self.view.clone(), self.selected));
+        self.view = next_view;
+        self.selected = 0;
+        self.refresh(songs);
+        None
+    }
+
+    /// Returns false when already at the top level.
+    pub fn back(&mut self, songs: &[Song]) -> bool {
+        match self.history.pop() {
+            Some((view, selected)) => {
+                self.view = view;
+                self.selected = selected;
+                self.refresh(songs);
+                true
+            }
+            None => false
+        }
+    }
+
+    pub fn title(&self) -> String {
+        match &self.view {
+            View::Categories => String::from("Library"),
+            View::Artists => String::from("Library / Artists"),
+            View::Albums(None) => String::from("Library / Albums"),
+            View::Albums(Some(artist)) => format!("Library / {}", label(artist, "Unknown Artist")),
+            View::Genres => String::from("Library / Genres"),
+            View::Years => String::from("Library / Years"),
+            View::Tracks(filter) => {
+                let mut title = String::from("Library");
+                if let Some(artist) = &filter.artist { title.push_str(&format!(" / {}", label(artist, "Unknown Artist"))); }
+                if let Some(album) = &filter.album { title.push_str(&format!(" / {}", label(album, "Unknown Album"))); }
+                if let Some(genre) = &filter.genre { title.push_str(&format!(" / {}", label(genre, "Unknown Genre"))); }
+                if let Some(year) = filter.year { title.push_str(&format!(" / {}", if year == 0 { String::from("Unknown Year") } else { year.to_string() })); }
+                if filter == &TrackFilter::default() { title.push_str(" / All Tracks"); }
+                title
+            }
+        }
+    }
+
+    pub fn draw<B: Backend>(&mut self, f: &mut Frame<B>, chunk: Rect, search: &Search) {
+        let (rows, row) = search.filter_view(&self.items, Some(self.selected));
+        SelectableList::default()
+            .block(Block::default().borders(Borders::ALL).title(&search.title(&self.title())))
+            .items(&rows)
+            .select(row)
+            .style(Style::default().fg(Color::White))
+            .highlight_style(Style::default().fg(Color::White).modifier(Modifier::BOLD))
+            .highlight_symbol(">")
+            .render(f, chunk);
+    }
+}
@@ -0,0 +1,364 @@
+use std::fs;
+use std::io::{ self, BufRead, BufReader, Write };
+use std::os::unix::net::{ UnixListener, UnixStream };
+use std::path::{ Path, PathBuf };
+use std::sync::mpsc;
+use std::thread;
+use termion::event::Key;
+use super::event::Event;
+use super::player::{ MediaPlayer, Song, MIN_RATE, MAX_RATE, format_timestamp, parse_timestamp };
+use super::order::PlayMode;
+use super::util;
+
+/// Subcommands that remote control a running muscli rather than start a new one.
+pub const SUBCOMMANDS: [&str; 14] = ["play", "pause", "play-pause", "stop", "next", "previous", "seek", "volume", "mode", "rate", "status", "queue",
+    "enqueue", "quit"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Seek {
+    To(u32),
+    Forward(u32),
+    Backward(u32),
+}
+
+/// A command read off the control socket, one per line.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Play,
+    Pause,
+    Toggle,
+    Stop,
+    Next,
+    Previous,
+    Seek(Seek),
+    // percent, 0 to 100
+    Volume(u32),
+    Mode(PlayMode),
+    // playback speed, MIN_RATE to MAX_RATE
+    Rate(f32),
+    Status,
+    Queue,
+    Enqueue(String),
+    Quit,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let line = line.trim();
+        let (name, arg) = match line.find(char::is_whitespace) {
+            Some(idx) => (&line[..idx], line[idx..].trim()),
+            None => (line, "")
+        };
+        let command = match name {
+            "play" => Command::Play,
+            "pause" => Command::Pause,
+            "toggle" => Command::Toggle,
+            "stop" => Command::Stop,
+            "next" => Command::Next,
+            "previous" => Command::Previous,
+            "seek" => match parse_seek(arg) {
+                Some(seek) => Command::Seek(seek),
+                None => return Err(format!("seek expects [+|-]seconds or [+|-]mm:ss, got '{}'", arg))
+            },
+            "volume" => match arg.parse::<u32>() {
+                Ok(percent) if percent <= 100 => Command::Volume(percent),
+                _ => return Err(format!("volume expects 0-100, got '{}'", arg))
+            },
+            "mode" => match PlayMode::from_name(arg) {
+                Some(mode) => Command::Mode(mode),
+                None => return Err(format!("mode expects sequential, repeat-all, repeat-one, stop-at-end, shuffle or album-shuffle, got '{}'", arg))
+            },
+            "rate" => match arg.parse::<f32>() {
+                Ok(rate) if rate >= MIN_RATE && rate <= MAX_RATE => Command::Rate(rate),
+                _ => return Err(format!("rate expects {}-{}, got '{}'", MIN_RATE, MAX_RATE, arg))
+            },
+            "status" => Command::Status,
+            "queue" => Command::Queue,
+            "enqueue" if arg.is_empty() => return Err(String::from("enqueue expects a path")),
+            "enqueue" => Command::Enqueue(String::from(arg)),
+            "quit" => Command::Quit,
+            "" => return Err(String::from("empty command")),
+            _ => return Err(format!("unknown command '{}'", name))
+        };
+        match command {
+            Command::Seek(_) | Command::Volume(_) | Command::Mode(_) | Command::Rate(_) | Command::Enqueue(_) => Ok(command),
+            _ if !arg.is_empty() => Err(format!("{} doesn't take an argument", name)),
+            _ => Ok(command)
+        }
+    }
+}
+
+fn parse_seek(text: &str) -> Option<Seek> {
+    if text.starts_with('+') {
+        parse_timestamp(&text[1..]).map(Seek::Forward)
+    } else if text.starts_with('-') {
+        parse_timestamp(&text[1..]).map(Seek::Backward)
+    } else {
+        parse_timestamp(text).map(Seek::To)
+    }
+}
+
+/// One command from a socket client. The main loop answers by sending a single line back on `reply`.
+pub struct Request {
+    pub command: Command,
+    pub reply: mpsc::Sender<String>,
+}
+
+/// Answer to the status command.
+#[derive(Serialize)]
+pub struct Status {
+    // "playing", "paused" or "stopped"
+    pub state: &'static str,
+    pub song: Option<Song>,
+    pub position_ms: u32,
+    pub length_ms: u32,
+    pub volume: f32,
+    pub muted: bool,
+    pub rate: f32,
+    pub play_mode: PlayMode,
+    pub queue_length: usize,
+}
+
+pub fn status(media_player: &MediaPlayer) -> Status {
+    let playing = media_player.now_playing.is_some();
+    Status {
+        state: if !playing { "stopped" } else if media_player.paused() { "paused" } else { "playing" },
+        song: media_player.now_playing.clone(),
+        position_ms: if playing { media_player.position().unwrap_or(0) } else { 0 },
+        length_ms: if playing { media_player.length().unwrap_or(0) } else { 0 },
+        volume: media_player.volume(),
+        muted: media_player.muted(),
+        rate: media_player.rate(),
+        play_mode: media_player.play_mode,
+        queue_length: media_player.queue.len(),
+    }
+}
+
+/// The line sent back for a command: "OK", "OK <json>" or "ERR <reason>".
+pub fn response(result: Result<Option<String>, String>) -> String {
+    match result {
+        Ok(Some(body)) => format!("OK {}", body),
+        Ok(None) => String::from("OK"),
+        Err(reason) => format!("ERR {}", reason)
+    }
+}
+
+/// Runs `command` on the main loop from another thread, returning the body of the OK response.
+pub fn request(tx: &mpsc::Sender<Event<Key>>, command: Command) -> Result<String, String> {
+    let (reply_tx, reply_rx) = mpsc::channel();
+    let gone = String::from("muscli is shutting down");
+    tx.send(Event::Command(Request { command: command, reply: reply_tx })).map_err(|_| gone.clone())?;
+    let response = reply_rx.recv().map_err(|_| gone)?;
+    if response.starts_with("ERR ") { return Err(String::from(&response[4..])); }
+    Ok(String::from(response.trim_start_matches("OK").trim_start()))
+}
+
+/// $XDG_RUNTIME_DIR/muscli.sock, or the data directory when there's no runtime directory.
+pub fn socket_path() -> Option<PathBuf> {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => Some(Path::new(&dir).join("muscli.sock")),
+        None => util::data_file("muscli.sock")
+    }
+}
+
+/// Binds the control socket and serves clients on background threads, handing each command
+/// to the main loop through `tx`. Fails if another muscli is already listening there.
+pub fn listen(path: &Path, tx: mpsc::Sender<Event<Key>>) -> io::Result<()> {
+    let listener = match UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("muscli is already listening on {}", path.display())));
+            }
+            // nobody answers, so it was left behind by a muscli that didn't shut down cleanly
+            fs::remove_file(path)?;
+            UnixListener::bind(path)?
+        }
+        Err(e) => return Err(e)
+    };
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Ok(stream) = stream {
+                let tx = tx.clone();
+                thread::spawn(move || serve(stream, tx));
+            }
+        }
+    });
+    Ok(())
+}
+
+pub fn unlisten(path: &Path) {
+    let _ = fs::remove_file(path);
+}
+
+fn serve(stream: UnixStream, tx: mpsc::Sender<Event<Key>>) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return
+        };
+        if line.trim().is_empty() { continue; }
+        let response = match Command::parse(&line) {
+            Ok(command) => {
+                let (reply_tx, reply_rx) = mpsc::channel();
+                if tx.send(Event::Command(Request { command: command, reply: reply_tx })).is_err() { return; }
+                match reply_rx.recv() {
+                    Ok(response) => response,
+                    Err(_) => return
+                }
+            }
+            Err(reason) => response(Err(reason))
+        };
+        if writeln!(writer, "{}", response).is_err() { return; }
+    }
+}
+
+// the protocol lines for a subcommand, checked here so typos fail without needing a running muscli
+fn request_lines(args: &[&str]) -> Result<Vec<String>, failure::Error> {
+    let (name, rest) = match args.split_first() {
+        Some((name, rest)) => (*name, rest),
+        None => return Err(failure::err_msg("no command given"))
+    };
+    let lines = match name {
+        "play-pause" if rest.is_empty() => vec![String::from("toggle")],
+        "enqueue" if rest.is_empty() => return Err(failure::err_msg("enqueue expects at least one path")),
+        // the daemon has its own working directory, so relative paths are resolved here
+        "enqueue" => rest.iter().map(|path| {
+            fs::canonicalize(path)
+                .map_err(|e| failure::err_msg(format!("{}: {}", path, e)))
+                .map(|path| format!("enqueue {}", path.display()))
+        }).collect::<Result<Vec<String>, failure::Error>>()?,
+        _ => vec![args.join(" ")]
+    };
+    for line in &lines {
+        Command::parse(line).map_err(failure::err_msg)?;
+    }
+    Ok(lines)
+}
+
+/// Writes `lines` to the control socket and returns one response per line.
+pub fn send(path: &Path, lines: &[String]) -> io::Result<Vec<String>> {
+    let mut stream = UnixStream::connect(path)?;
+    for line in lines {
+        writeln!(stream, "{}", line)?;
+    }
+    BufReader::new(stream).lines().take(lines.len()).collect()
+}
+
+fn describe_status(json: &str) -> Result<String, failure::Error> {
+    let status: serde_json::Value = serde_json::from_str(json)?;
+    let state = status["state"].as_str().unwrap_or("stopped");
+    let song = &status["song"];
+    if state == "stopped" || song.is_null() { return Ok(String::from("stopped")); }
+    let ms = |key: &str| format_timestamp(status[key].as_u64().unwrap_or(0) as u32);
+    Ok(format!("{}: {}  {} / {}", state, describe_song(song), ms("position_ms"), ms("length_ms")))
+}
+
+fn describe_song(song: &serde_json::Value) -> String {
+    let name = song["name"].as_str().unwrap_or("");
+    match song["artist"].as_str() {
+        Some(artist) if !artist.is_empty() => format!("{} - {}", artist, name),
+        _ => String::from(name)
+    }
+}
+
+/// Runs a remote control subcommand against the muscli listening on the control socket.
+/// status and queue print JSON with --json, a line of text otherwise.
+pub fn run(args: &[String]) -> Result<(), failure::Error> {
+    let json = args.iter().any(|arg| arg == "--json");
+    let args: Vec<&str> = args.iter().map(String::as_str).filter(|&arg| arg != "--json").collect();
+    let lines = request_lines(&args)?;
+    let path = socket_path().ok_or_else(|| failure::err_msg("no directory for the control socket"))?;
+    let responses = send(&path, &lines)
+        .map_err(|e| failure::err_msg(format!("couldn't reach muscli at {} ({}), is it running?", path.display(), e)))?;
+
+    for response in responses {
+        if response.starts_with("ERR ") { return Err(failure::err_msg(String::from(&response[4..]))); }
+        let body = response.trim_start_matches("OK").trim_start();
+        match args[0] {
+            "status" if !json => println!("{}", describe_status(body)?),
+            "queue" if !json => {
+                let songs: Vec<serde_json::Value> = serde_json::from_str(body)?;
+                if songs.is_empty() { println!("queue is empty"); }
+                for song in &songs { println!("{}", describe_song(song)); }
+            }
+            _ => { if !body.is_empty() { println!("{}", body); } }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn commands_parse() {
+        assert_eq!(Command::parse("play"), Ok(Command::Play));
+        assert_eq!(Command::parse("  next \n"), Ok(Command::Next));
+        assert_eq!(Command::parse("seek 1:30"), Ok(Command::Seek(Seek::To(90000))));
+        assert_eq!(Command::parse("seek +30"), Ok(Command::Seek(Seek::Forward(30000))));
+        assert_eq!(Command::parse("seek -0:05"), Ok(Command::Seek(Seek::Backward(5000))));
+        assert_eq!(Command::parse("volume 40"), Ok(Command::Volume(40)));
+        assert!(Command::parse("volume 140").is_err());
+        assert_eq!(Command::parse("mode repeat-one"), Ok(Command::Mode(PlayMode::RepeatOne)));
+        assert_eq!(Command::parse("mode AlbumShuffle"), Ok(Command::Mode(PlayMode::AlbumShuffle)));
+        assert!(Command::parse("mode loud").is_err());
+        assert_eq!(Command::parse("rate 1.25"), Ok(Command::Rate(1.25)));
+        assert!(Command::parse("rate 10").is_err());
+        assert_eq!(Command::parse("enqueue /music/a song.mp3"), Ok(Command::Enqueue(String::from("/music/a song.mp3"))));
+        assert!(Command::parse("seek soon").is_err());
+        assert!(Command::parse("enqueue").is_err());
+        assert!(Command::parse("pause now").is_err());
+        assert!(Command::parse("dance").is_err());
+    }
+
+    #[test]
+    fn socket_round_trips_commands() {
+        let path = std::env::temp_dir().join(format!("muscli-control-test-{}.sock", process::id()));
+        let (tx, rx) = mpsc::channel();
+        listen(&path, tx.clone()).unwrap();
+        assert!(listen(&path, tx).is_err());
+
+        // stands in for the main loop
+        thread::spawn(move || {
+            while let Ok(Event::Command(request)) = rx.recv() {
+                let result = if request.command == Command::Status { Ok(Some(String::from("{}"))) } else { Ok(None) };
+                let _ = request.reply.send(response(result));
+            }
+        });
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        writeln!(stream, "status\nbogus\npause").unwrap();
+        let lines: Vec<String> = BufReader::new(stream).lines().take(3).map(|line| line.unwrap()).collect();
+        unlisten(&path);
+        assert_eq!(lines, vec!["OK {}", "ERR unknown command 'bogus'", "OK"]);
+    }
+
+    #[test]
+    fn subcommands_become_protocol_lines() {
+        assert_eq!(request_lines(&["play-pause"]).unwrap(), vec!["toggle"]);
+        assert_eq!(request_lines(&["seek", "+30"]).unwrap(), vec!["seek +30"]);
+        assert_eq!(request_lines(&["status"]).unwrap(), vec!["status"]);
+        assert!(request_lines(&["seek", "later"]).is_err());
+        assert!(request_lines(&["next", "please"]).is_err());
+        assert!(request_lines(&["enqueue"]).is_err());
+        assert!(request_lines(&["enqueue", "/no/such/file.mp3"]).is_err());
+
+        let dir = std::env::temp_dir();
+        let enqueued = request_lines(&["enqueue", dir.to_str().unwrap()]).unwrap();
+        assert_eq!(enqueued, vec![format!("enqueue {}", fs::canonicalize(&dir).unwrap().display())]);
+    }
+
+    #[test]
+    fn status_reads_as_a_line() {
+        assert_eq!(describe_status(r#"{"state":"stopped","song":null}"#).unwrap(), "stopped");
+        let json = r#"{"state":"paused","song":{"name":"Song","artist":"Band"},"position_ms":61000,"length_ms":200000}"#;
+        assert_eq!(describe_status(json).unwrap(), "paused: Band - Song  1:01 / 3:20");
+    }
+}
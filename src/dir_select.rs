@@ -1,5 +1,3 @@
-const SUPPORTED_FORMATED: [&str; 15] = ["aiff", "asf", "asx", "dls", "flac", "fsb", "it", "m3u", "mp3", "midi", "mod", "ogg", "pls", "s3m", "wav"];
-
 use std::fs::{ DirEntry, read_dir };
 use termion::event::Key;
 use tui::backend::Backend;
@@ -8,18 +6,49 @@ use tui::style::{ Color, Style};
 use tui::layout::{ Rect, Layout, Constraint, Direction };
 use tui::terminal::Frame;
 use super::App;
+use super::library::SUPPORTED_FORMATS;
+use super::search::Search;
 
+// the directory listing is fuzzy filtered by whatever has been typed after the last '/',
+// and tab cycles the input through the matching entries
 #[derive(Clone)]
 pub struct DirSelect {
     input: String,
-    valid_files: Vec<String>
+    valid_files: Vec<String>,
+    search: Search,
+    completion: Option<usize>,
 }
 
 impl DirSelect {
     pub fn new() -> DirSelect {
         DirSelect {
             input: String::new(),
-            valid_files: Vec::new()
+            valid_files: Vec::new(),
+            search: Search::new(),
+            completion: None,
+        }
+    }
+
+    fn typed_segment(&self) -> &str {
+        match self.input.rfind('/') {
+            Some(idx) => &self.input[idx + 1..],
+            None => &self.input
+        }
+    }
+
+    fn update_filter(&mut self) {
+        let segment = String::from(self.typed_segment());
+        self.search.set_query(&segment, &self.valid_files);
+        self.completion = None;
+    }
+
+    fn complete(&mut self) {
+        self.completion = self.search.step(self.completion, true);
+        if let Some(idx) = self.completion {
+            let name = String::from(self.valid_files[idx].trim_start_matches('/'));
+            let keep = match self.input.rfind('/') { Some(idx) => idx + 1, None => 0 };
+            self.input.truncate(keep);
+            self.input.push_str(&name);
         }
     }
     
@@ -34,7 +63,7 @@ impl DirSelect {
             } else {
                 if let Some(os_str_ext) = file.path().as_path().extension() {
                     if let Some(ext) = os_str_ext.to_str() {
-                        if SUPPORTED_FORMATED.contains(&ext) {
+                        if SUPPORTED_FORMATS.contains(&ext) {
                             self.valid_files.push(String::from(file_name));
                         }
                     }
@@ -45,7 +74,8 @@ impl DirSelect {
     
     fn rebuild_file_list(&mut self) {
         self.valid_files.clear();
-        if let Ok(path) = read_dir(&self.input) { // requires full path, can't use ~/ for now
+        let dir = match self.input.rfind('/') { Some(idx) => &self.input[..idx + 1], None => &self.input };
+        if let Ok(path) = read_dir(dir) { // requires full path, can't use ~/ for now
             for file in path {
                 if let Ok(file) = file {
                     if let Ok(meta) = file.metadata() {
@@ -65,16 +95,22 @@ impl DirSelect {
             Key::Char('\n') => {
                 app.rebuild_local_with_dir(&self.input);
             }
+            Key::Char('\t') => {
+                self.complete();
+            }
             Key::Char('/') => {
                 self.input.push('/');
                 self.rebuild_file_list();
+                self.update_filter();
             }
             Key::Char(c) => {
                 self.input.push(c);
+                self.update_filter();
             }
             Key::Backspace => {
                 self.input.pop();
                 self.rebuild_file_list();
+                self.update_filter();
             }
             _ => {}
         }
@@ -92,7 +128,10 @@ impl DirSelect {
             .style(Style::default().fg(Color::Yellow))
             .block(Block::default().borders(Borders::ALL).title("Input"))
             .render(f, chunks[0]);
-        let messages = self.valid_files.iter().enumerate().map(|(_i, m)| Text::raw(format!("{}", m)));
+        let completion = self.completion;
+        let messages = self.valid_files.iter().enumerate()
+            .filter(|(i, _m)| self.search.matches().contains(i))
+            .map(|(i, m)| Text::raw(if Some(i) == completion { format!("> {}", m) } else { format!("{}", m) }));
         List::new(messages)
             .block(Block::default().borders(Borders::ALL))
             .render(f, chunks[1]);
@@ -5,17 +5,22 @@ use std::time::Duration;
 
 use termion::event::Key;
 use termion::input::TermRead;
+use super::control::Request;
+use super::mpd;
 
 pub enum Event<I> {
     Input(I),
     Tick,
+    Command(Request),
+    Mpd(mpd::Request),
 }
 
 /// A small event handler that wrap termion input and tick events. Each event
 /// type is handled in its own thread and returned to a common `Receiver`
 pub struct Events {
     rx: mpsc::Receiver<Event<Key>>,
-    input_handle: thread::JoinHandle<()>,
+    tx: mpsc::Sender<Event<Key>>,
+    input_handle: Option<thread::JoinHandle<()>>,
     tick_handle: thread::JoinHandle<()>,
 }
 
@@ -37,11 +42,20 @@ impl Events {
         Events::with_config(Config::default())
     }
 
+    /// Ticks only, for running without a terminal.
+    pub fn headless() -> Events {
+        Events::spawn(Config::default(), false)
+    }
+
     pub fn with_config(config: Config) -> Events {
+        Events::spawn(config, true)
+    }
+
+    fn spawn(config: Config, read_input: bool) -> Events {
         let (tx, rx) = mpsc::channel();
-        let input_handle = {
+        let input_handle = if read_input {
             let tx = tx.clone();
-            thread::spawn(move || {
+            Some(thread::spawn(move || {
                 let stdin = io::stdin();
                 for evt in stdin.keys() {
                     match evt {
@@ -53,7 +67,9 @@ impl Events {
                         Err(_) => {}
                     }
                 }
-            })
+            }))
+        } else {
+            None
         };
         let tick_handle = {
             let tx = tx.clone();
@@ -69,11 +85,17 @@ impl Events {
         };
         Events {
             rx,
+            tx,
             input_handle,
             tick_handle,
         }
     }
 
+    /// For other threads to feed events into the same loop, like the control socket does.
+    pub fn sender(&self) -> mpsc::Sender<Event<Key>> {
+        self.tx.clone()
+    }
+
     pub fn next(&self) -> Result<Event<Key>, mpsc::RecvError> {
         self.rx.recv()
     }
@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::time::Instant;
+use super::audio::{ AudioBackend, SoundId, ChannelId, PcmFormat };
+
+// bytes of pcm read per call while decoding
+const DECODE_CHUNK_BYTES: usize = 64 * 1024;
+// fmod's pitch shifter only goes from 0.5 to 2.0, so two are chained to cover 1/3 for triple speed
+const PITCH_SHIFT_STAGES: usize = 2;
+const PITCHSHIFT_PITCH: i32 = 0;
+
+fn fmod_error(what: &str, status: rfmod::Status) -> failure::Error {
+    failure::err_msg(format!("{}: {:?}", what, status))
+}
+
+/// `AudioBackend` on top of the FMOD bindings. Handles are kept in maps so the rest of the app only sees ids.
+pub struct FmodBackend {
+    sys: rfmod::Sys,
+    sounds: HashMap<usize, rfmod::Sound>,
+    channels: HashMap<usize, rfmod::Channel>,
+    // which sound each channel is playing, to work out how much of it is left
+    channel_sounds: HashMap<usize, usize>,
+    // pitch shifters added once a channel's rate is first changed, bypassed again at 1.0
+    pitch_shifters: HashMap<usize, Vec<rfmod::Dsp>>,
+    rates: HashMap<usize, f32>,
+    started: Instant,
+    next_id: usize,
+}
+
+impl FmodBackend {
+    pub fn new() -> Result<FmodBackend, failure::Error> {
+        let sys = rfmod::Sys::new().map_err(|e| fmod_error("FMOD Sys::new failed", e))?;
+        match sys.init() {
+            rfmod::Status::Ok => {}
+            e => return Err(fmod_error("FMOD init failed", e))
+        };
+        Ok(FmodBackend {
+            sys,
+            sounds: HashMap::new(),
+            channels: HashMap::new(),
+            channel_sounds: HashMap::new(),
+            pitch_shifters: HashMap::new(),
+            rates: HashMap::new(),
+            started: Instant::now(),
+            next_id: 0,
+        })
+    }
+
+    fn next_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn forget_channel(&mut self, id: usize) {
+        self.channel_sounds.remove(&id);
+        self.rates.remove(&id);
+        if let Some(shifters) = self.pitch_shifters.remove(&id) {
+            for mut dsp in shifters {
+                dsp.remove();
+                dsp.release();
+            }
+        }
+    }
+
+    fn base_frequency(&self, channel: usize) -> Option<f32> {
+        self.channel_sounds.get(&channel)
+            .and_then(|s| self.sounds.get(s))
+            .and_then(|s| s.get_defaults().ok())
+            .map(|(frequency, _, _, _)| frequency)
+    }
+
+    fn pitch_shifters_for(&mut self, channel: usize) -> Result<&Vec<rfmod::Dsp>, failure::Error> {
+        if !self.pitch_shifters.contains_key(&channel) {
+            let handle = match self.channels.get(&channel) {
+                Some(handle) => handle,
+                None => return Err(failure::err_msg("channel was stopped"))
+            };
+            let mut shifters = Vec::new();
+            for _ in 0..PITCH_SHIFT_STAGES {
+                let dsp = self.sys.create_DSP_by_type(rfmod::DspType::PitchShift).map_err(|e| fmod_error("Pitch shifter", e))?;
+                handle.add_DSP(&dsp).map_err(|e| fmod_error("Add pitch shifter", e))?;
+                shifters.push(dsp);
+            }
+            self.pitch_shifters.insert(channel, shifters);
+        }
+        Ok(&self.pitch_shifters[&channel])
+    }
+}
+
+// samples come out in whatever format the codec produces, always little endian
+fn read_pcm(sound: &rfmod::Sound, sink: &mut dyn FnMut(PcmFormat, &[f32])) -> Result<(), failure::Error> {
+    let (_, sample_format, channels, _) = sound.get_format().map_err(|e| fmod_error("Format", e))?;
+    let (rate, _, _, _) = sound.get_defaults().map_err(|e| fmod_error("Defaults", e))?;
+    let width = match sample_format {
+        rfmod::SoundFormat::PCM8 => 1,
+        rfmod::SoundFormat::PCM16 => 2,
+        rfmod::SoundFormat::PCM24 => 3,
+        rfmod::SoundFormat::PCM32 | rfmod::SoundFormat::PCMFloat => 4,
+        other => return Err(failure::err_msg(format!("can't decode {:?} samples", other)))
+    };
+    let format = PcmFormat { rate: rate as u32, channels: channels.max(1) as usize };
+    let frame = (width * format.channels) as u32;
+    let chunk = DECODE_CHUNK_BYTES as u32 - DECODE_CHUNK_BYTES as u32 % frame;
+    let total = sound.get_length(rfmod::TIMEUNIT_PCMBYTES).map_err(|e| fmod_error("Length", e))?;
+    let mut samples = Vec::with_capacity(chunk as usize / width);
+    let mut offset = 0u32;
+
+    // seek the decoder to each chunk and lock it out, locked lengths are in bytes but seeks in frames
+    while offset < total {
+        match sound.seek_data(offset / frame) {
+            rfmod::Status::Ok => {}
+            rfmod::Status::FileEOF => return Ok(()),
+            e => return Err(fmod_error("Seek", e))
+        }
+        let (first, second) = match sound.lock(offset, chunk.min(total - offset)) {
+            Ok(locked) => locked,
+            Err(rfmod::Status::FileEOF) => return Ok(()),
+            Err(e) => return Err(fmod_error("Decode", e))
+        };
+        // the lock can wrap around the end of the buffer, which hands back two pieces
+        let bytes: Vec<u8> = first.iter().chain(&second).cloned().collect();
+        samples.clear();
+        for sample in bytes[..bytes.len() - bytes.len() % width].chunks(width) {
+            samples.push(match sample_format {
+                rfmod::SoundFormat::PCM8 => sample[0] as i8 as f32 / 128.0,
+                rfmod::SoundFormat::PCM16 => i16::from_le_bytes([sample[0], sample[1]]) as f32 / 32768.0,
+                rfmod::SoundFormat::PCM24 => (i32::from_le_bytes([0, sample[0], sample[1], sample[2]]) >> 8) as f32 / 8388608.0,
+                rfmod::SoundFormat::PCM32 => i32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]) as f32 / 2147483648.0,
+                _ => f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]])
+            });
+        }
+        sound.unlock(first, second);
+        if bytes.is_empty() { return Ok(()); }
+        sink(format, &samples);
+        offset += bytes.len() as u32;
+    }
+    Ok(())
+}
+
+impl AudioBackend for FmodBackend {
+    fn load(&mut self, uri: &str) -> Result<SoundId, failure::Error> {
+        let sound = self.sys.create_sound(uri, None, None).map_err(|e| fmod_error(&format!("Couldn't open {}", uri), e))?;
+        let id = self.next_id();
+        self.sounds.insert(id, sound);
+        Ok(SoundId(id))
+    }
+
+    fn release(&mut self, sound: SoundId) {
+        if let Some(mut sound) = self.sounds.remove(&sound.0) {
+            sound.release();
+        }
+    }
+
+    fn play(&mut self, sound: SoundId) -> Result<ChannelId, failure::Error> {
+        let channel = match self.sounds.get(&sound.0) {
+            Some(sound) => sound.play().map_err(|e| fmod_error("Play", e))?,
+            None => return Err(failure::err_msg("sound was released"))
+        };
+        // finished channels are only stopped, not freed by us, so drop the ones fmod is done with
+        let finished: Vec<usize> = self.channels.iter().filter(|(_, c)| !c.is_playing().unwrap_or(false)).map(|(&id, _)| id).collect();
+        for id in finished {
+            self.channels.remove(&id);
+            self.forget_channel(id);
+        }
+        let id = self.next_id();
+        self.channels.insert(id, channel);
+        self.channel_sounds.insert(id, sound.0);
+        Ok(ChannelId(id))
+    }
+
+    fn play_after(&mut self, sound: SoundId, after: ChannelId, overlap_ms: u32) -> Result<ChannelId, failure::Error> {
+        // samples left in the current sound less the overlap, converted to the mixer's rate at the
+        // channel's current frequency, which includes any rate change
+        let remaining = match (self.channels.get(&after.0), self.channel_sounds.get(&after.0).and_then(|s| self.sounds.get(s))) {
+            (Some(channel), Some(playing)) => {
+                let length = playing.get_length(rfmod::TIMEUNIT_PCM).map_err(|e| fmod_error("Length", e))? as f64;
+                let position = channel.get_position(rfmod::TIMEUNIT_PCM).map_err(|e| fmod_error("Position", e))? as f64;
+                let (base_frequency, _, _, _) = playing.get_defaults().map_err(|e| fmod_error("Defaults", e))?;
+                let frequency = channel.get_frequency().map_err(|e| fmod_error("Frequency", e))? as f64;
+                let output_rate = self.sys.get_software_format().map_err(|e| fmod_error("Software format", e))?.sample_rate;
+                let overlap = overlap_ms as f64 * base_frequency as f64 / 1000.0;
+                ((length - position - overlap).max(0.0) * output_rate as f64 / frequency) as u64
+            }
+            _ => return self.play(sound)
+        };
+        let (hi, lo) = self.sys.get_DSP_clock().map_err(|e| fmod_error("DSP clock", e))?;
+        let start = (((hi as u64) << 32) | lo as u64) + remaining;
+
+        let channel = self.play(sound)?;
+        if let Some(handle) = self.channels.get(&channel.0) {
+            // hold it silent until the delay is set, otherwise the first few samples leak out early
+            handle.set_paused(true);
+            handle.set_delay(rfmod::DelayType::DSPClockStart, (start >> 32) as usize, (start & 0xffff_ffff) as usize);
+            handle.set_paused(false);
+        }
+        Ok(channel)
+    }
+
+    fn stop(&mut self, channel: ChannelId) {
+        if let Some(handle) = self.channels.remove(&channel.0) {
+            handle.stop();
+        }
+        self.forget_channel(channel.0);
+    }
+
+    fn set_paused(&mut self, channel: ChannelId, paused: bool) {
+        if let Some(channel) = self.channels.get(&channel.0) {
+            channel.set_paused(paused);
+        }
+    }
+
+    fn is_paused(&self, channel: ChannelId) -> bool {
+        match self.channels.get(&channel.0) {
+            Some(channel) => channel.get_paused().unwrap_or(false),
+            None => false
+        }
+    }
+
+    fn is_playing(&self, channel: ChannelId) -> bool {
+        match self.channels.get(&channel.0) {
+            Some(channel) => channel.is_playing().unwrap_or(false),
+            None => false
+        }
+    }
+
+    fn seek(&mut self, channel: ChannelId, position_ms: u32) {
+        if let Some(channel) = self.channels.get(&channel.0) {
+            channel.set_position(position_ms as usize, rfmod::TIMEUNIT_MS);
+        }
+    }
+
+    fn set_volume(&mut self, channel: ChannelId, volume: f32) {
+        if let Some(channel) = self.channels.get(&channel.0) {
+            channel.set_volume(volume);
+        }
+    }
+
+    fn volume(&self, channel: ChannelId) -> f32 {
+        match self.channels.get(&channel.0) {
+            Some(channel) => channel.get_volume().unwrap_or(0.0),
+            None => 0.0
+        }
+    }
+
+    // speeding up raises the pitch by the same factor, so the shifters bring it back down by 1/rate
+    fn set_rate(&mut self, channel: ChannelId, rate: f32) {
+        match (self.channels.get(&channel.0), self.base_frequency(channel.0)) {
+            (Some(handle), Some(frequency)) => { handle.set_frequency(frequency * rate); }
+            _ => return
+        }
+        self.rates.insert(channel.0, rate);
+        if rate == 1.0 && !self.pitch_shifters.contains_key(&channel.0) { return; }
+        if let Ok(shifters) = self.pitch_shifters_for(channel.0) {
+            let pitch = (1.0 / rate).powf(1.0 / PITCH_SHIFT_STAGES as f32);
+            for dsp in shifters {
+                dsp.set_parameter(PITCHSHIFT_PITCH, pitch);
+                dsp.set_bypass(rate == 1.0);
+            }
+        }
+    }
+
+    fn rate(&self, channel: ChannelId) -> f32 {
+        self.rates.get(&channel.0).cloned().unwrap_or(1.0)
+    }
+
+    fn position(&self, channel: ChannelId) -> Option<u32> {
+        self.channels.get(&channel.0).and_then(|c| c.get_position(rfmod::TIMEUNIT_MS).ok()).map(|p| p as u32)
+    }
+
+    fn length(&self, sound: SoundId) -> Option<u32> {
+        self.sounds.get(&sound.0).and_then(|s| s.get_length(rfmod::TIMEUNIT_MS).ok())
+    }
+
+    fn waveform(&self, channel: ChannelId, samples: usize) -> Vec<f32> {
+        match self.channels.get(&channel.0).and_then(|c| c.get_wave_data(samples, 1).ok()) {
+            Some(data) => data,
+            None => vec![0.0; samples]
+        }
+    }
+
+    fn spectrum(&self, channel: ChannelId, bands: usize) -> Vec<f32> {
+        match self.channels.get(&channel.0).and_then(|c| c.get_spectrum(bands, None, None).ok()) {
+            Some(data) => data,
+            None => vec![0.0; bands]
+        }
+    }
+
+    fn now_ms(&self) -> u64 {
+        let elapsed = self.started.elapsed();
+        elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64
+    }
+
+    fn decode(&mut self, uri: &str, sink: &mut dyn FnMut(PcmFormat, &[f32])) -> Result<(), failure::Error> {
+        // opened without a channel or prebuffering, read_pcm then decodes straight from the file
+        let mut sound = self.sys.create_sound(uri, Some(rfmod::Mode(rfmod::OPENONLY)), None).map_err(|e| fmod_error(&format!("Couldn't open {}", uri), e))?;
+        let result = read_pcm(&sound, sink);
+        sound.release();
+        result
+    }
+
+    fn update(&mut self) {
+        self.sys.update();
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::VecDeque;
+use std::time::{ Duration, Instant };
+use termion::event::Key;
+use tui::backend::Backend;
+use tui::widgets::{ Widget, Block, Borders, SelectableList };
+use tui::style::{ Color, Modifier, Style};
+use tui::layout::Rect;
+use tui::terminal::Frame;
+use super::player::{ Player, Song };
+use super::MediaPlayer;
+
+const HISTORY_LEN: usize = 100;
+// roughly how long pandora keeps a track's audio url alive
+const STREAM_URL_LIFETIME: Duration = Duration::from_secs(30 * 60);
+
+pub struct HistoryEntry {
+    pub song: Song,
+    pub played_at: Instant,
+}
+
+impl HistoryEntry {
+    pub fn is_stream(&self) -> bool {
+        self.song.is_stream()
+    }
+
+    // local files can always be replayed, streamed tracks only while their url is still valid
+    pub fn playable(&self) -> bool {
+        !self.is_stream() || self.played_at.elapsed() < STREAM_URL_LIFETIME
+    }
+}
+
+/// Recently played songs, newest last, capped at `HISTORY_LEN` entries.
+pub struct History {
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History { entries: VecDeque::new() }
+    }
+
+    pub fn push(&mut self, song: Song) {
+        if self.entries.len() >= HISTORY_LEN {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(HistoryEntry { song: song, played_at: Instant::now() });
+    }
+
+    /// The most recent entry that can still be played, without removing it.
+    pub fn peek_playable(&self) -> Option<&Song> {
+        self.entries.iter().rev().find(|e| e.playable()).map(|e| &e.song)
+    }
+
+    /// Removes and returns the most recent playable entry. Expired streams in the way are dropped.
+    pub fn pop_playable(&mut self) -> Option<Song> {
+        while let Some(entry) = self.entries.pop_back() {
+            if entry.playable() { return Some(entry.song); }
+        }
+        None
+    }
+
+    /// Newest first.
+    pub fn entries(&self) -> impl Iterator<Item = &HistoryEntry> {
+        self.entries.iter().rev()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+pub struct HistoryView {
+    selected: Option<usize>,
+}
+
+impl HistoryView {
+    pub fn new() -> HistoryView {
+        HistoryView { selected: None }
+    }
+
+    fn titles(media_player: &MediaPlayer) -> Vec<String> {
+        media_player.history.entries().map(|e| {
+            let minutes = e.played_at.elapsed().as_secs() / 60;
+            let expired = if e.playable() { "" } else { " (expired)" };
+            let name = if e.song.artist.is_empty() { e.song.name.clone() } else { format!("{} - {}", e.song.artist, e.song.name) };
+            format!("{:>4}m ago  {}{}", minutes, name, expired)
+        }).collect()
+    }
+}
+
+impl Player for HistoryView {
+    fn input(&mut self, key: Key, media_player: &mut MediaPlayer) {
+        let len = media_player.history.len();
+        match key {
+            Key::Down if len > 0 => {
+                self.selected = match self.selected { Some(selected) if selected + 1 < len => Some(selected + 1), _ => Some(0) };
+            }
+            Key::Up if len > 0 => {
+                self.selected = match self.selected { Some(selected) if selected > 0 && selected < len => Some(selected - 1), _ => Some(len - 1) };
+            }
+            Key::Char('\n') => {
+                let song = self.selected
+                    .and_then(|selected| media_player.history.entries().nth(selected))
+                    .filter(|e| e.playable())
+                    .map(|e| e.song.clone());
+                if let Some(song) = song {
+                    media_player.play_song(song);
+                    self.selected = Some(0);
+                }
+            }
+            Key::Char('e') => {
+                let song = self.selected.and_then(|selected| media_player.history.entries().nth(selected)).map(|e| e.song.clone());
+                if let Some(song) = song { media_player.queue.push(song); }
+            }
+            Key::Char(' ') => {
+                if media_player.now_playing.is_some() { media_player.toggle_pause(); }
+            }
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, _media_player: &mut MediaPlayer) {
+    }
+
+    fn draw<B: Backend>(&mut self, f: &mut Frame<B>, chunk: Rect, media_player: &mut MediaPlayer) {
+        let titles = HistoryView::titles(media_player);
+        if media_player.now_playing.is_some() {
+            media_player.draw(f, chunk, "Recently Played", titles, self.selected.unwrap_or(0));
+        } else {
+            SelectableList::default()
+                .block(Block::default().borders(Borders::ALL).title("Recently Played"))
+                .items(&titles)
+                .select(self.selected)
+                .style(Style::default().fg(Color::White))
+                .highlight_style(Style::default().fg(Color::White).modifier(Modifier::BOLD))
+                .highlight_symbol(">")
+                .render(f, chunk);
+        }
+    }
+}
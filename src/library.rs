@@ -0,0 +1,244 @@
+use std::collections::{ HashMap, HashSet };
+use std::fs::{ self, File, read_dir };
+use std::io::{ BufReader, BufWriter };
+use std::path::{ Path, PathBuf };
+use std::time::UNIX_EPOCH;
+use super::player::Song;
+use super::{ tags, util };
+use super::replaygain::ReplayGain;
+
+// what fmod can decode
+pub const SUPPORTED_FORMATS: [&str; 16] = ["aiff", "asf", "asx", "dls", "flac", "fsb", "it", "m3u", "mp3", "midi", "mod", "oga", "ogg", "pls", "s3m", "wav"];
+
+// bump whenever tag reading changes so cached entries get re-read
+const INDEX_VERSION: u32 = 5;
+
+// fmod opens these but they only point at other files, so they never become songs
+const PLAYLIST_FORMATS: [&str; 3] = ["asx", "m3u", "pls"];
+
+#[derive(Debug)]
+pub struct ScanError {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+pub struct LibraryScan {
+    pub songs: Vec<Song>,
+    pub errors: Vec<ScanError>,
+}
+
+pub fn extension_of(path: &Path) -> Option<String> {
+    path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase())
+}
+
+pub fn is_audio_file(path: &Path) -> bool {
+    match extension_of(path) {
+        Some(ext) => SUPPORTED_FORMATS.contains(&ext.as_str()) && !PLAYLIST_FORMATS.contains(&ext.as_str()),
+        None => false
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Index {
+    version: u32,
+    entries: HashMap<String, IndexEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexEntry {
+    mtime: u64,
+    size: u64,
+    song: Song,
+}
+
+/// Tag cache persisted under the data directory, keyed by path. An entry is reused
+/// as long as the file's mtime and size still match, so rescans only read changed files.
+pub struct Library {
+    index: Index,
+    index_path: Option<PathBuf>,
+    dirty: bool,
+}
+
+impl Library {
+    /// An empty library that's never written to disk.
+    pub fn new() -> Library {
+        Library {
+            index: Index { version: INDEX_VERSION, entries: HashMap::new() },
+            index_path: None,
+            dirty: false,
+        }
+    }
+
+    pub fn load() -> Library {
+        let index_path = util::data_file("library.json");
+        let index: Option<Index> = index_path.as_ref()
+            .and_then(|p| File::open(p).ok())
+            .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok());
+        let index = match index {
+            Some(index) if index.version == INDEX_VERSION => index,
+            _ => Index { version: INDEX_VERSION, entries: HashMap::new() }
+        };
+
+        Library {
+            index: index,
+            index_path: index_path,
+            dirty: false,
+        }
+    }
+
+    /// Walks `root` and every directory below it. Files that can't be read are left out
+    /// of `songs` and listed in `errors`; index entries under `root` that no longer exist are pruned.
+    pub fn scan(&mut self, root: &str) -> LibraryScan {
+        let mut scan = LibraryScan { songs: Vec::new(), errors: Vec::new() };
+        let mut seen = HashSet::new();
+        self.scan_recursive(Path::new(root), &mut scan, &mut seen);
+
+        let root_path = Path::new(root);
+        let indexed = self.index.entries.len();
+        self.index.entries.retain(|path, _| !Path::new(path).starts_with(root_path) || seen.contains(path));
+        if self.index.entries.len() != indexed { self.dirty = true; }
+
+        if self.dirty {
+            if let Err(e) = self.save() {
+                let path = self.index_path.clone().unwrap_or_default();
+                scan.errors.push(ScanError { path: path, reason: format!("couldn't save library index: {}", e) });
+            }
+        }
+
+        scan.songs.sort_by(|a, b| a.path.cmp(&b.path));
+        scan
+    }
+
+    pub fn save(&mut self) -> Result<(), failure::Error> {
+        let index_path = match self.index_path.as_ref() {
+            Some(p) => p,
+            None => return Err(failure::err_msg("no data directory"))
+        };
+        // write beside the index and rename so a crash mid-write can't corrupt it
+        let tmp_path = index_path.with_extension("json.tmp");
+        serde_json::to_writer(BufWriter::new(File::create(&tmp_path)?), &self.index)?;
+        fs::rename(&tmp_path, index_path)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn scan_recursive(&mut self, dir: &Path, scan: &mut LibraryScan, seen: &mut HashSet<String>) {
+        let entries = match read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                scan.errors.push(ScanError { path: dir.to_path_buf(), reason: e.to_string() });
+                return;
+            }
+        };
+
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    scan.errors.push(ScanError { path: dir.to_path_buf(), reason: e.to_string() });
+                    continue;
+                }
+            };
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(file_type) => {
+                    // symlinked directories are skipped so a link back up the tree can't loop forever
+                    if file_type.is_dir() {
+                        self.scan_recursive(&path, scan, seen);
+                    } else if is_audio_file(&path) {
+                        match self.song_for(&path) {
+                            Ok(song) => {
+                                seen.insert(song.path.clone());
+                                scan.songs.push(song);
+                            }
+                            Err(e) => scan.errors.push(ScanError { path: path, reason: e.to_string() }),
+                        }
+                    }
+                }
+                Err(e) => scan.errors.push(ScanError { path: path, reason: e.to_string() }),
+            }
+        }
+    }
+
+    /// Stores measured gain for an indexed song. The entry is re-stamped, so having just written
+    /// the gain into the file's tags doesn't make the next scan read it again.
+    pub fn set_replay_gain(&mut self, path: &str, replay_gain: ReplayGain) {
+        let stamp = file_stamp(Path::new(path)).ok();
+        if let Some(entry) = self.index.entries.get_mut(path) {
+            entry.song.replay_gain = replay_gain;
+            if let Some((mtime, size)) = stamp {
+                entry.mtime = mtime;
+                entry.size = size;
+            }
+            self.dirty = true;
+        }
+    }
+
+    fn song_for(&mut self, path: &Path) -> Result<Song, failure::Error> {
+        let (mtime, size) = file_stamp(path)?;
+        let key = match path.to_str() {
+            Some(s) => String::from(s),
+            None => return Err(failure::err_msg("path is not valid UTF-8"))
+        };
+
+        if let Some(entry) = self.index.entries.get(&key) {
+            if entry.mtime == mtime && entry.size == size {
+                return Ok(entry.song.clone());
+            }
+        }
+
+        let song = read_song(path)?;
+        self.index.entries.insert(key, IndexEntry { mtime: mtime, size: size, song: song.clone() });
+        self.dirty = true;
+        Ok(song)
+    }
+}
+
+// (mtime, size), what decides whether an index entry is still current
+fn file_stamp(path: &Path) -> Result<(u64, u64), failure::Error> {
+    let meta = fs::metadata(path)?;
+    let mtime = match meta.modified()?.duration_since(UNIX_EPOCH) { Ok(d) => d.as_secs(), Err(_) => 0 };
+    Ok((mtime, meta.len()))
+}
+
+fn title_from_file_name(path: &Path) -> String {
+    match path.file_stem().and_then(|stem| stem.to_str()) {
+        Some(stem) => String::from(stem),
+        None => String::new()
+    }
+}
+
+pub fn read_song(path: &Path) -> Result<Song, failure::Error> {
+    let path_str = match path.to_str() {
+        Some(s) => s,
+        None => return Err(failure::err_msg("path is not valid UTF-8"))
+    };
+    File::open(path)?;
+
+    let mut song = Song {
+        name: title_from_file_name(path),
+        path: String::from(path_str),
+        artist: String::new(),
+        album: String::new(),
+        genre: String::new(),
+        year: 0,
+        track: 0,
+        length: 0,
+        replay_gain: ReplayGain::default(),
+        art_url: String::new(),
+    };
+
+    // broken or missing tags aren't fatal, the file can still be played under its file name
+    if let Ok(tags) = tags::read_tags(path) {
+        if let Some(title) = tags.title { song.name = title; }
+        song.artist = tags.artist.unwrap_or_default();
+        song.album = tags.album.unwrap_or_default();
+        song.genre = tags.genre.unwrap_or_default();
+        song.year = tags.year.unwrap_or(0);
+        song.track = tags.track.unwrap_or(0);
+        song.length = tags.length.unwrap_or(0);
+        song.replay_gain = tags.replay_gain;
+    }
+
+    Ok(song)
+}
@@ -1,7 +1,5 @@
 use termion::event::Key;
-use rfmod::Sys;
-use id3::Tag;
-use glob::glob;
+use std::path::Path;
 use tui::backend::Backend;
 use tui::widgets::{ Widget, Block, Borders, SelectableList };
 use tui::style::{ Color, Modifier, Style};
@@ -11,6 +9,10 @@ use super::player::Player;
 use super::Config;
 use super::MediaPlayer;
 use super::player::{ Song, Playlist };
+use super::library::{ Library, ScanError };
+use super::browser::Browser;
+use super::search::Search;
+use super::order::{ PlayMode, PlayOrder };
 
 pub struct LocalPlayer {
     config: Config,
@@ -19,14 +21,31 @@ pub struct LocalPlayer {
     playing_song: Option<usize>,
     song_list: Vec<String>,
     rebuild_song_list: bool,
+    scan_errors: Vec<ScanError>,
+    library: Library,
+    library_songs: Vec<Song>,
+    browser: Browser,
+    browsing: bool,
+    search: Search,
+    order: PlayOrder,
+    preloaded_song: Option<usize>,
 }
 
 impl LocalPlayer {
     pub fn new(config: Config) -> LocalPlayer {
         let path = config.local_dir.clone();
+        let mut library = Library::load();
+        let (default_playlist, scan_errors) = LocalPlayer::build_playlist_from_directory(&mut library, &path);
+        LocalPlayer::with_playlist(config, library, default_playlist, scan_errors)
+    }
+
+    pub fn with_playlist(config: Config, library: Library, default_playlist: Playlist, scan_errors: Vec<ScanError>) -> LocalPlayer {
         let mut song_list = Vec::new();
-        let default_playlist = LocalPlayer::build_playlist_from_directory(&path).unwrap();
         for s in &default_playlist.songs { song_list.push(s.name.clone()); }
+        let library_songs = default_playlist.songs.clone();
+        let mut browser = Browser::new();
+        browser.refresh(&library_songs);
+        let order = PlayOrder::new(PlayMode::RepeatAll, &default_playlist.songs, None);
 
         LocalPlayer {
             config: config,
@@ -35,44 +54,239 @@ impl LocalPlayer {
             playing_song: None,
             song_list: song_list,
             rebuild_song_list: false,
+            scan_errors: scan_errors,
+            library: library,
+            library_songs: library_songs,
+            browser: browser,
+            browsing: false,
+            search: Search::new(),
+            order: order,
+            preloaded_song: None,
+        }
+    }
+
+    pub fn set_directory(&mut self, path: &str, mode: PlayMode) {
+        let (playlist, scan_errors) = LocalPlayer::build_playlist_from_directory(&mut self.library, path);
+        self.config.local_dir = String::from(path);
+        self.song_list = playlist.songs.iter().map(|s| s.name.clone()).collect();
+        self.library_songs = playlist.songs.clone();
+        self.playlist = playlist;
+        self.scan_errors = scan_errors;
+        self.selected_song = None;
+        self.playing_song = None;
+        self.browser.reset(&self.library_songs);
+        self.search.clear();
+        self.order = PlayOrder::new(mode, &self.playlist.songs, None);
+        self.preloaded_song = None;
+    }
+
+    /// Path of the song under the cursor in the playlist, saved with the session.
+    pub fn selected_path(&self) -> Option<String> {
+        self.selected_song.and_then(|idx| self.playlist.songs.get(idx)).map(|s| s.path.clone())
+    }
+
+    /// Puts the cursor back on `selected` and follows whatever `media_player` restored, both by path.
+    pub fn restore(&mut self, selected: Option<&str>, media_player: &MediaPlayer) {
+        let songs = &self.playlist.songs;
+        let position = |path: &str| songs.iter().position(|s| s.path == path);
+        let selected_song = selected.and_then(|path| position(path));
+        let playing_song = media_player.now_playing.as_ref().and_then(|song| position(&song.path));
+        self.selected_song = selected_song;
+        self.playing_song = playing_song;
+        if let Some(idx) = self.playing_song { self.order.seek(idx); }
+        self.rebuild_song_list = true;
+    }
+
+    fn play_index(&mut self, media_player: &mut MediaPlayer, idx: usize) {
+        self.playing_song = Some(idx);
+        self.order.seek(idx);
+        media_player.play_song(self.playlist.songs[idx].clone());
+    }
+
+    fn skip(&mut self, media_player: &mut MediaPlayer, forward: bool) {
+        if let Some(current) = self.playing_song {
+            let target = if forward {
+                self.order.next(media_player.play_mode, &self.playlist.songs, current)
+            } else {
+                self.order.previous(media_player.play_mode, current)
+            };
+            match target {
+                Some(idx) => self.play_index(media_player, idx),
+                None => {
+                    media_player.stop();
+                    self.playing_song = None;
+                }
+            }
+        }
+    }
+
+    pub fn next(&mut self, media_player: &mut MediaPlayer) {
+        self.skip(media_player, true);
+    }
+
+    /// Replays the last history entry, or steps back in the playback order once history runs out.
+    pub fn previous(&mut self, media_player: &mut MediaPlayer) {
+        match media_player.previous() {
+            Some(song) => {
+                if let Some(idx) = self.playlist.songs.iter().position(|s| s.path == song.path) {
+                    self.playing_song = Some(idx);
+                    self.order.seek(idx);
+                }
+            }
+            None => self.skip(media_player, false)
+        }
+    }
+
+    pub fn stop(&mut self, media_player: &mut MediaPlayer) {
+        media_player.stop();
+        self.playing_song = None;
+    }
+
+    /// Switches play mode, starting the new order from the playing song.
+    pub fn set_play_mode(&mut self, mode: PlayMode, media_player: &mut MediaPlayer) {
+        media_player.play_mode = mode;
+        self.order = PlayOrder::new(mode, &self.playlist.songs, self.playing_song);
+    }
+
+    /// Every song found under the local directory, whatever the playlist currently shows.
+    pub fn library_songs(&self) -> &[Song] {
+        &self.library_songs
+    }
+
+    pub fn capturing_input(&self) -> bool {
+        self.search.editing()
+    }
+
+    fn search_haystacks(&self) -> Vec<String> {
+        if self.browsing {
+            self.browser.items.clone()
+        } else {
+            self.playlist.songs.iter().map(|s| format!("{} {} {}", s.name, s.artist, s.album)).collect()
+        }
+    }
+
+    fn step_search(&mut self, forward: bool) {
+        if self.browsing {
+            self.browser.selected = self.search.step(Some(self.browser.selected), forward).unwrap_or(0);
+        } else {
+            self.selected_song = self.search.step(self.selected_song, forward);
+            self.rebuild_song_list = true;
         }
     }
 
-    fn build_playlist_from_directory(path: &str) -> Result<Playlist, failure::Error> {
-        let mut glob_path = String::from(path);
-        let mut songs: Vec<Song> = Vec::new();
-        let mut total_length = 0;
-        glob_path.push_str("/*.mp3");
-        for entry in glob(&glob_path).expect("Failed to read glob pattern.") {
-            match entry {
-                Ok(path) => {
-                    let tag = Tag::read_from_path(path.to_str().unwrap()).unwrap();
-                    let song = Song { 
-                        name: String::from(match tag.title() { Some(s) => s, None => "" }), 
-                        path: String::from(match path.to_str() { Some(s) => s, None => "" }),
-                        artist: String::from(match tag.artist() { Some(s) => s, None => "" }),
-                        album: String::from(match tag.album() { Some(s) => s, None => "" }),
-                        length: match tag.duration() { Some(i) => i, None => 0 },
-                    };
-                    total_length += song.length;
-                    songs.push(song);
-                },
-                Err(e) => println!("{:?}", e),
+    // replaces the playlist with a browser track listing and starts the picked track
+    fn play_from_browser(&mut self, media_player: &mut MediaPlayer, tracks: Vec<usize>, idx: usize) {
+        let songs: Vec<Song> = tracks.iter().map(|&i| self.library_songs[i].clone()).collect();
+        self.playlist = Playlist { name: self.browser.title(), length: songs.iter().map(|s| s.length).sum(), songs: songs };
+        self.selected_song = Some(idx);
+        self.rebuild_song_list = true;
+        self.browsing = false;
+        self.order = PlayOrder::new(media_player.play_mode, &self.playlist.songs, Some(idx));
+        self.play_index(media_player, idx);
+    }
+
+    // the song under the cursor, either in the playlist or in a browser track listing
+    fn selected_song(&self) -> Option<Song> {
+        if self.browsing {
+            self.browser.selected_track().map(|idx| self.library_songs[idx].clone())
+        } else {
+            self.selected_song.map(|idx| self.playlist.songs[idx].clone())
+        }
+    }
+
+    fn build_playlist_from_directory(library: &mut Library, path: &str) -> (Playlist, Vec<ScanError>) {
+        let scan = library.scan(path);
+        let total_length = scan.songs.iter().map(|s| s.length).sum();
+        let name = match Path::new(path).file_name().and_then(|name| name.to_str()) {
+            Some(name) => String::from(name),
+            None => String::from(path)
+        };
+
+        (Playlist { name: name, songs: scan.songs, length: total_length }, scan.errors)
+    }
+
+    // media_player switches to the preloaded song by itself, catch up once it has
+    fn follow_preloaded(&mut self, media_player: &MediaPlayer) {
+        if let Some(preloaded) = self.preloaded_song {
+            if !media_player.has_prepared() {
+                self.preloaded_song = None;
+                let path = self.playlist.songs.get(preloaded).map(|s| &s.path);
+                if path.is_some() && media_player.now_playing.as_ref().map(|s| &s.path) == path {
+                    self.playing_song = Some(preloaded);
+                    self.order.seek(preloaded);
+                }
             }
         }
+    }
 
-        Ok(Playlist { name: songs[0].album.clone(), songs: songs, length: total_length })
+    fn playlist_title(&self) -> String {
+        if self.scan_errors.is_empty() {
+            format!("Playlist: {}", self.playlist.name)
+        } else {
+            format!("Playlist: {} ({} unreadable, first: {} - {})", self.playlist.name, self.scan_errors.len(), self.scan_errors[0].path.display(), self.scan_errors[0].reason)
+        }
     }
 }
 
 impl Player for LocalPlayer {
-    fn input(&mut self, key: Key, fmod: &Sys, media_player: &mut MediaPlayer) {
+    fn input(&mut self, key: Key, media_player: &mut MediaPlayer) {
+        let haystacks = self.search_haystacks();
+        if self.search.input(key, &haystacks) {
+            if self.browsing {
+                self.browser.selected = self.search.constrain(Some(self.browser.selected)).unwrap_or(0);
+            } else {
+                self.selected_song = self.search.constrain(self.selected_song);
+                self.rebuild_song_list = true;
+            }
+            return;
+        }
+        if self.search.active() {
+            match key {
+                Key::Down | Key::Char('n') => { self.step_search(true); return; }
+                Key::Up | Key::Char('N') => { self.step_search(false); return; }
+                _ => {}
+            }
+        }
+
+        if self.browsing {
+            match key {
+                Key::Down => { self.browser.next(); return; }
+                Key::Up => { self.browser.previous(); return; }
+                Key::Char('\n') => {
+                    self.search.clear();
+                    if let Some((tracks, idx)) = self.browser.enter(&self.library_songs) {
+                        self.play_from_browser(media_player, tracks, idx);
+                    }
+                    return;
+                }
+                Key::Backspace => {
+                    self.search.clear();
+                    if !self.browser.back(&self.library_songs) { self.browsing = false; }
+                    return;
+                }
+                _ => {}
+            }
+        }
+
         match key {
-            Key::Char('s') => {
-                media_player.pause();
-                self.playing_song = None;
+            Key::Char('b') => {
+                self.browsing = !self.browsing;
+                self.search.clear();
             }
-            Key::Down => {
+            Key::Char('s') => self.stop(media_player),
+            Key::Char('m') => {
+                let mode = media_player.play_mode.next();
+                self.set_play_mode(mode, media_player);
+            }
+            Key::Char('n') => self.next(media_player),
+            Key::Char('p') => self.previous(media_player),
+            Key::Char('e') => {
+                if let Some(song) = self.selected_song() { media_player.queue.push(song); }
+            }
+            Key::Char('E') => {
+                if let Some(song) = self.selected_song() { media_player.queue.insert_next(song); }
+            }
+            Key::Down if !self.song_list.is_empty() => {
                 self.selected_song = if let Some(selected) = self.selected_song {
                     if selected >= self.song_list.len() - 1 {
                         Some(0)
@@ -84,7 +298,7 @@ impl Player for LocalPlayer {
                 };
                 self.rebuild_song_list = true;
             }
-            Key::Up => {
+            Key::Up if !self.song_list.is_empty() => {
                 self.selected_song = if let Some(selected) = self.selected_song {
                     if selected > 0 {
                         Some(selected - 1)
@@ -96,21 +310,10 @@ impl Player for LocalPlayer {
                 };
                 self.rebuild_song_list = true;
             }
-            Key::Char('x') => {
-                if self.playing_song != None {
-                    media_player.back();
-                }
-            }
-            Key::Char('z') => {
-                if self.playing_song != None {
-                    media_player.forward();
-                }                    
-            }
             Key::Char(' ') => {
                 if self.selected_song != None {
                     if self.selected_song != self.playing_song {
-                        self.playing_song = self.selected_song;
-                        media_player.play_from_uri(fmod, &self.playlist.songs[self.playing_song.unwrap()].path);
+                        self.play_index(media_player, self.selected_song.unwrap());
                     } else {
                         media_player.toggle_pause();
                     }
@@ -120,7 +323,7 @@ impl Player for LocalPlayer {
         }
     }
 
-    fn tick(&mut self, fmod: &Sys, media_player: &mut MediaPlayer) {
+    fn tick(&mut self, media_player: &mut MediaPlayer) {
         // draw > in list
         if self.rebuild_song_list && self.selected_song != None {
             self.song_list.clear();
@@ -131,34 +334,154 @@ impl Player for LocalPlayer {
             self.rebuild_song_list = false;
         }
 
+        // hand the next song over early so media_player can start it without a gap
+        self.follow_preloaded(media_player);
+        if let Some(current) = self.playing_song {
+            if media_player.wants_next() {
+                if let Some(idx) = self.order.next(media_player.play_mode, &self.playlist.songs, current) {
+                    if media_player.prepare_next(self.playlist.songs[idx].clone()) {
+                        self.preloaded_song = Some(idx);
+                    }
+                }
+            }
+        }
+        self.follow_preloaded(media_player);
+
         // go to next song
         if self.playing_song != None {
             if media_player.almost_over() {
-                self.playing_song = if self.playing_song.unwrap() >= self.song_list.len() - 1 { Some(0) } else { Some(self.playing_song.unwrap() + 1) };
-                media_player.play_from_uri(fmod, &self.playlist.songs[self.playing_song.unwrap()].path);
+                self.skip(media_player, true);
             }
         }
     }
 
     fn draw<B: Backend>(&mut self, f: &mut Frame<B>, chunk: Rect, media_player: &mut MediaPlayer) {
         if let Some(idx) = self.playing_song {
-            media_player.draw(f, chunk, self.playlist.name.as_str(), 
-                                         self.song_list.clone(), 
-                                         idx,
-                                         String::from(""),
-                                         String::from(""));
+            let (list_title, (list, list_idx)) = if self.browsing {
+                (self.search.title(&self.browser.title()), self.search.filter_view(&self.browser.items, Some(self.browser.selected)))
+            } else {
+                (self.search.title(&self.playlist.name), self.search.filter_view(&self.song_list, Some(idx)))
+            };
+            media_player.draw(f, chunk, &list_title,
+                                         list,
+                                         list_idx.unwrap_or(0));
+        } else if self.browsing {
+            self.browser.draw(f, chunk, &self.search);
         } else {
             let chunks = Layout::default()
                 .direction(Direction::Horizontal)
                 .constraints(vec![Constraint::Percentage(100)])
                 .split(chunk);
+            let (rows, _) = self.search.filter_view(&self.song_list, None);
             SelectableList::default()
-                .block(Block::default().borders(Borders::ALL).title(&format!("Playlist: {}", self.playlist.name)))
-                .items(&self.song_list)
+                .block(Block::default().borders(Borders::ALL).title(&self.search.title(&self.playlist_title())))
+                .items(&rows)
                 .select(self.playing_song)
                 .style(Style::default().fg(Color::White))
                 .highlight_style(Style::default().fg(Color::White).modifier(Modifier::BOLD))
                 .render(f, chunks[chunks.len() - 1]);
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::audio::ManualClock;
+    use super::super::audio::testing::{ self, media_player };
+
+    fn song(path: &str) -> Song {
+        Song { length: 1000, ..testing::song(path) }
+    }
+
+    fn setup(paths: &[&str]) -> (LocalPlayer, MediaPlayer, ManualClock) {
+        let lengths: Vec<(&str, u32)> = paths.iter().map(|p| (*p, 1000)).collect();
+        let (media_player, clock) = media_player(Config::default(), &lengths);
+        let songs: Vec<Song> = paths.iter().map(|p| song(p)).collect();
+        let playlist = Playlist { name: String::from("test"), length: 1000 * songs.len() as u32, songs: songs };
+        let local_player = LocalPlayer::with_playlist(Config::default(), Library::new(), playlist, Vec::new());
+        (local_player, media_player, clock)
+    }
+
+    // same order as App::tick
+    fn tick(local_player: &mut LocalPlayer, media_player: &mut MediaPlayer) {
+        media_player.tick();
+        local_player.tick(media_player);
+    }
+
+    fn playing(media_player: &MediaPlayer) -> Option<String> {
+        media_player.now_playing.as_ref().map(|s| s.path.clone())
+    }
+
+    #[test]
+    fn tick_advances_to_next_song() {
+        let (mut local_player, mut media_player, clock) = setup(&["a.mp3", "b.mp3"]);
+        media_player.play_mode = PlayMode::Sequential;
+        local_player.play_index(&mut media_player, 0);
+
+        tick(&mut local_player, &mut media_player);
+        assert_eq!(playing(&media_player), Some(String::from("a.mp3")));
+
+        clock.advance(1000);
+        tick(&mut local_player, &mut media_player);
+        assert_eq!(local_player.playing_song, Some(1));
+        assert_eq!(playing(&media_player), Some(String::from("b.mp3")));
+
+        // nothing after the last song in order
+        clock.advance(1000);
+        tick(&mut local_player, &mut media_player);
+        assert_eq!(local_player.playing_song, None);
+        assert_eq!(playing(&media_player), None);
+    }
+
+    #[test]
+    fn repeat_all_wraps_around() {
+        let (mut local_player, mut media_player, clock) = setup(&["a.mp3", "b.mp3"]);
+        local_player.play_index(&mut media_player, 1);
+        clock.advance(1000);
+        tick(&mut local_player, &mut media_player);
+        assert_eq!(local_player.playing_song, Some(0));
+        assert_eq!(playing(&media_player), Some(String::from("a.mp3")));
+    }
+
+    #[test]
+    fn space_plays_selected_song() {
+        let (mut local_player, mut media_player, _clock) = setup(&["a.mp3", "b.mp3"]);
+        local_player.input(Key::Down, &mut media_player);
+        local_player.input(Key::Down, &mut media_player);
+        local_player.input(Key::Char(' '), &mut media_player);
+        assert_eq!(playing(&media_player), Some(String::from("b.mp3")));
+        local_player.input(Key::Char(' '), &mut media_player);
+        assert!(media_player.paused());
+    }
+
+    #[test]
+    fn restore_finds_songs_by_path() {
+        let (mut local_player, mut media_player, _clock) = setup(&["a.mp3", "b.mp3", "c.mp3"]);
+        media_player.restore(song("c.mp3"), 400);
+        local_player.restore(Some("b.mp3"), &media_player);
+        assert_eq!(local_player.selected_path(), Some(String::from("b.mp3")));
+        assert_eq!(local_player.playing_song, Some(2));
+        assert!(media_player.paused());
+        assert_eq!(media_player.position(), Some(400));
+
+        local_player.restore(Some("gone.mp3"), &media_player);
+        assert_eq!(local_player.selected_song, None);
+    }
+
+    #[test]
+    fn next_song_is_preloaded_and_followed() {
+        let (mut local_player, mut media_player, clock) = setup(&["a.mp3", "b.mp3", "c.mp3"]);
+        local_player.play_index(&mut media_player, 0);
+        tick(&mut local_player, &mut media_player);
+        assert_eq!(local_player.preloaded_song, Some(1));
+        assert!(media_player.has_prepared());
+
+        clock.advance(1030);
+        tick(&mut local_player, &mut media_player);
+        assert_eq!(local_player.playing_song, Some(1));
+        assert_eq!(playing(&media_player), Some(String::from("b.mp3")));
+        assert_eq!(media_player.position(), Some(30));
+        assert_eq!(local_player.preloaded_song, Some(2));
+    }
+}
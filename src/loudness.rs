@@ -0,0 +1,360 @@
+use std::collections::{ BTreeMap, VecDeque };
+use std::f64::consts::PI;
+use std::path::Path;
+use super::Config;
+use super::audio::{ self, AudioBackend };
+use super::library::Library;
+use super::player::Song;
+use super::replaygain::ReplayGain;
+use super::tags;
+
+// ReplayGain 2.0 aims everything at -18 LUFS
+const REFERENCE_LUFS: f64 = -18.0;
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+// gating blocks are 400ms long and start every 100ms, so each spans four 100ms steps
+const STEPS_PER_BLOCK: usize = 4;
+// true peak is found by interpolating 3 extra points between samples from this many neighbours
+const OVERSAMPLE: usize = 4;
+const PEAK_TAPS: usize = 12;
+
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 3]) -> Biquad {
+        Biquad { b: b, a: a, x: [0.0; 2], y: [0.0; 2] }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1] - self.a[1] * self.y[0] - self.a[2] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+// BS.1770 K-weighting: a high shelf for the head's acoustics, then a high pass. The spec only lists
+// 48kHz coefficients, these are the analog prototypes re-derived for any rate.
+fn k_weighting(rate: f64) -> (Biquad, Biquad) {
+    let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new([1.0, -2.0, 1.0], [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+    (shelf, high_pass)
+}
+
+fn lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+fn power(lufs: f64) -> f64 {
+    10f64.powf((lufs + 0.691) / 10.0)
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// Gated integrated loudness in LUFS, None when everything is below the absolute gate.
+pub fn integrated(blocks: &[f64]) -> Option<f64> {
+    let gated: Vec<f64> = blocks.iter().cloned().filter(|&p| p > power(ABSOLUTE_GATE_LUFS)).collect();
+    if gated.is_empty() { return None; }
+    let relative_gate = lufs(mean(&gated)) + RELATIVE_GATE_LU;
+    let gated: Vec<f64> = gated.into_iter().filter(|&p| p > power(relative_gate)).collect();
+    if gated.is_empty() { None } else { Some(lufs(mean(&gated))) }
+}
+
+/// The mean square of every gating block, kept so tracks can be pooled into an album, and the true peak.
+#[derive(Clone, Debug, Default)]
+pub struct Measurement {
+    pub blocks: Vec<f64>,
+    pub peak: f32,
+}
+
+impl Measurement {
+    pub fn loudness(&self) -> Option<f64> {
+        integrated(&self.blocks)
+    }
+
+    /// ReplayGain in dB to bring this up or down to the reference level.
+    pub fn gain(&self) -> Option<f32> {
+        self.loudness().map(|l| (REFERENCE_LUFS - l) as f32)
+    }
+
+    /// An album is gated as one long track, not averaged from its tracks' loudness.
+    pub fn album(tracks: &[Measurement]) -> Measurement {
+        Measurement {
+            blocks: tracks.iter().flat_map(|t| t.blocks.iter().cloned()).collect(),
+            peak: tracks.iter().map(|t| t.peak).fold(0.0, f32::max),
+        }
+    }
+}
+
+/// EBU R128 meter for one stream of interleaved samples.
+pub struct Meter {
+    channels: usize,
+    weights: Vec<f64>,
+    filters: Vec<(Biquad, Biquad)>,
+    step_frames: usize,
+    // weighted sum of squares over the current 100ms step, and how many frames are in it
+    step_sum: f64,
+    step_len: usize,
+    steps: VecDeque<f64>,
+    // recent samples per channel for peak interpolation, oldest first
+    history: Vec<VecDeque<f32>>,
+    phases: Vec<[f64; PEAK_TAPS]>,
+    measurement: Measurement,
+}
+
+impl Meter {
+    pub fn new(rate: u32, channels: usize) -> Meter {
+        // 5.1 in the usual order: the lfe channel is ignored and the surrounds count a bit more
+        let weights = (0..channels).map(|c| match (channels, c) {
+            (6, 3) => 0.0,
+            (6, 4) | (6, 5) => 1.41,
+            _ => 1.0
+        }).collect();
+        // windowed sinc taps for each point between the middle two samples of the history
+        let phases = (1..OVERSAMPLE).map(|phase| {
+            let mut taps = [0.0; PEAK_TAPS];
+            for (i, tap) in taps.iter_mut().enumerate() {
+                let x = (PEAK_TAPS / 2 - 1) as f64 + phase as f64 / OVERSAMPLE as f64 - i as f64;
+                let window = 0.5 * (1.0 + (PI * x / (PEAK_TAPS / 2) as f64).cos());
+                *tap = (PI * x).sin() / (PI * x) * window;
+            }
+            taps
+        }).collect();
+
+        Meter {
+            channels: channels,
+            weights: weights,
+            filters: (0..channels).map(|_| k_weighting(rate as f64)).collect(),
+            step_frames: (rate as usize / 10).max(1),
+            step_sum: 0.0,
+            step_len: 0,
+            steps: VecDeque::new(),
+            history: vec![VecDeque::from(vec![0.0; PEAK_TAPS]); channels],
+            phases: phases,
+            measurement: Measurement::default(),
+        }
+    }
+
+    pub fn feed(&mut self, samples: &[f32]) {
+        for frame in samples.chunks(self.channels) {
+            for (c, &sample) in frame.iter().enumerate() {
+                let (shelf, high_pass) = &mut self.filters[c];
+                let weighted = high_pass.process(shelf.process(sample as f64));
+                self.step_sum += self.weights[c] * weighted * weighted;
+                self.track_peak(c, sample);
+            }
+            self.step_len += 1;
+            if self.step_len == self.step_frames {
+                self.finish_step();
+            }
+        }
+    }
+
+    /// A trailing partial block is dropped, as the spec does.
+    pub fn finish(self) -> Measurement {
+        self.measurement
+    }
+
+    fn track_peak(&mut self, channel: usize, sample: f32) {
+        let history = &mut self.history[channel];
+        history.pop_front();
+        history.push_back(sample);
+        let mut peak = self.measurement.peak.max(sample.abs());
+        for taps in &self.phases {
+            let value: f64 = taps.iter().zip(history.iter()).map(|(t, &s)| t * s as f64).sum();
+            peak = peak.max(value.abs() as f32);
+        }
+        self.measurement.peak = peak;
+    }
+
+    fn finish_step(&mut self) {
+        self.steps.push_back(self.step_sum / self.step_len as f64);
+        self.step_sum = 0.0;
+        self.step_len = 0;
+        if self.steps.len() > STEPS_PER_BLOCK { self.steps.pop_front(); }
+        if self.steps.len() == STEPS_PER_BLOCK {
+            self.measurement.blocks.push(self.steps.iter().sum::<f64>() / STEPS_PER_BLOCK as f64);
+        }
+    }
+}
+
+/// Decodes `uri` through the backend and meters all of it.
+pub fn measure(backend: &mut dyn AudioBackend, uri: &str) -> Result<Measurement, failure::Error> {
+    let mut meter: Option<Meter> = None;
+    backend.decode(uri, &mut |format, samples| {
+        meter.get_or_insert_with(|| Meter::new(format.rate, format.channels)).feed(samples);
+    })?;
+    match meter {
+        Some(meter) => Ok(meter.finish()),
+        None => Err(failure::err_msg("no audio decoded"))
+    }
+}
+
+// songs share album gain when they have the same album tag in the same directory
+fn album_key(song: &Song) -> Option<(String, String)> {
+    if song.album.is_empty() { return None; }
+    let dir = Path::new(&song.path).parent().and_then(|d| d.to_str()).unwrap_or("");
+    Some((String::from(dir), song.album.clone()))
+}
+
+fn needs_scan(song: &Song) -> bool {
+    song.replay_gain.track_gain.is_none() || (album_key(song).is_some() && song.replay_gain.album_gain.is_none())
+}
+
+/// Measures `songs` a whole album at a time, skipping albums (and loose tracks) that already have
+/// gain unless `force` is set. Files that fail to decode are reported and left out of their album.
+pub fn scan_songs(backend: &mut dyn AudioBackend, songs: &[Song], force: bool, progress: &mut dyn FnMut(&str)) -> Vec<(String, ReplayGain)> {
+    let mut albums: BTreeMap<(String, String), Vec<&Song>> = BTreeMap::new();
+    let mut groups: Vec<Vec<&Song>> = Vec::new();
+    for song in songs {
+        match album_key(song) {
+            Some(key) => albums.entry(key).or_insert_with(Vec::new).push(song),
+            None => groups.push(vec![song])
+        }
+    }
+    groups.extend(albums.into_iter().map(|(_, songs)| songs));
+
+    let mut results = Vec::new();
+    for group in groups {
+        if !force && !group.iter().any(|song| needs_scan(song)) { continue; }
+
+        let mut measured = Vec::new();
+        for song in group.iter() {
+            match measure(backend, &song.path) {
+                Ok(measurement) => {
+                    progress(&match measurement.loudness() {
+                        Some(loudness) => format!("{}: {:.1} LUFS, peak {:.3}", song.path, loudness, measurement.peak),
+                        None => format!("{}: silent", song.path)
+                    });
+                    measured.push((song, measurement));
+                }
+                Err(e) => progress(&format!("{}: {}", song.path, e))
+            }
+        }
+
+        let album = if album_key(group[0]).is_some() {
+            Some(Measurement::album(&measured.iter().map(|(_, m)| m.clone()).collect::<Vec<_>>()))
+        } else {
+            None
+        };
+        for (song, measurement) in measured {
+            results.push((song.path.clone(), ReplayGain {
+                track_gain: measurement.gain(),
+                track_peak: Some(measurement.peak),
+                album_gain: album.as_ref().and_then(|a| a.gain()),
+                album_peak: album.as_ref().map(|a| a.peak),
+            }));
+        }
+    }
+    results
+}
+
+/// `muscli scan-loudness [--force] [--write-tags]`: measures the local library and stores the
+/// gains in the library index, and with `--write-tags` in the files themselves.
+pub fn run(config: Config, args: &[String]) -> Result<(), failure::Error> {
+    let mut force = false;
+    let mut write_tags = false;
+    for arg in args {
+        match arg.as_str() {
+            "--force" => force = true,
+            "--write-tags" => write_tags = true,
+            other => return Err(failure::err_msg(format!("unknown option {}", other)))
+        }
+    }
+
+    let mut backend = audio::create_backend(&config.audio_backend)?;
+    let mut library = Library::load();
+    let scan = library.scan(&config.local_dir);
+    for error in &scan.errors {
+        println!("{}: {}", error.path.display(), error.reason);
+    }
+
+    let results = scan_songs(&mut *backend, &scan.songs, force, &mut |line| println!("{}", line));
+    for (path, gain) in &results {
+        if write_tags {
+            if let Err(e) = tags::write_replay_gain(Path::new(path), gain) {
+                println!("{}: couldn't write tags: {}", path, e);
+            }
+        }
+        library.set_replay_gain(path, gain.clone());
+    }
+    library.save()?;
+    println!("measured {} of {} songs", results.len(), scan.songs.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::audio::{ NullBackend, PcmFormat };
+    use super::super::audio::testing;
+
+    fn sine(amplitude: f32, seconds: usize) -> Vec<f32> {
+        (0..48000 * seconds)
+            .map(|i| amplitude * (2.0 * PI * 1000.0 * i as f64 / 48000.0).sin() as f32)
+            .flat_map(|s| vec![s, s])
+            .collect()
+    }
+
+    fn song(path: &str, album: &str) -> Song {
+        Song { album: String::from(album), ..testing::song(path) }
+    }
+
+    #[test]
+    fn stereo_sine_at_minus_23_dbfs_reads_minus_23_lufs() {
+        let mut meter = Meter::new(48000, 2);
+        meter.feed(&sine(10f32.powf(-23.0 / 20.0), 10));
+        let measurement = meter.finish();
+        assert!((measurement.loudness().unwrap() + 23.0).abs() < 0.1);
+        assert!((measurement.gain().unwrap() - 5.0).abs() < 0.1);
+        assert!((measurement.peak - 0.0708).abs() < 0.001);
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        let mut meter = Meter::new(44100, 1);
+        meter.feed(&vec![0.0; 44100 * 2]);
+        assert_eq!(meter.finish().loudness(), None);
+    }
+
+    #[test]
+    fn album_gain_pools_its_tracks() {
+        let mut backend = NullBackend::new();
+        let format = PcmFormat { rate: 48000, channels: 2 };
+        backend.set_pcm("loud.flac", format, sine(0.5, 3));
+        backend.set_pcm("quiet.flac", format, sine(0.05, 3));
+        let songs = vec![song("loud.flac", "A"), song("quiet.flac", "A"), song("broken.flac", "A")];
+        let mut lines = Vec::new();
+        let results = scan_songs(&mut backend, &songs, false, &mut |line| lines.push(String::from(line)));
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(lines.len(), 3);
+        let (loud, quiet) = (&results[0].1, &results[1].1);
+        assert!((quiet.track_gain.unwrap() - loud.track_gain.unwrap() - 20.0).abs() < 0.1);
+        assert_eq!(loud.album_gain, quiet.album_gain);
+        assert_eq!(quiet.album_peak, loud.track_peak);
+        // the relative gate leaves the quiet track out of the album's loudness
+        assert!((loud.album_gain.unwrap() - loud.track_gain.unwrap()).abs() < 0.1);
+
+        let tagged: Vec<Song> = songs.into_iter().map(|s| Song { replay_gain: loud.clone(), ..s }).collect();
+        assert!(scan_songs(&mut backend, &tagged, false, &mut |_| {}).is_empty());
+    }
+}
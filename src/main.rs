@@ -1,4 +1,7 @@
+#[cfg(feature = "fmod")]
 extern crate rfmod;
+#[cfg(all(target_os = "linux", feature = "mpris"))]
+extern crate dbus;
 
 #[macro_use]
 extern crate serde_derive;
@@ -8,6 +11,7 @@ extern crate dirs;
 extern crate base64;
 extern crate reqwest; 
 extern crate rspotify;
+extern crate rand;
 
 pub mod util;
 pub mod event;
@@ -17,10 +21,27 @@ pub mod player;
 pub mod dir_select;
 pub mod lyrics;
 pub mod spotify;
+pub mod library;
+pub mod tags;
+pub mod browser;
+pub mod search;
+pub mod queue;
+pub mod order;
+pub mod history;
+pub mod audio;
+pub mod state;
+pub mod replaygain;
+pub mod loudness;
+pub mod control;
+pub mod mpd;
+#[cfg(feature = "fmod")]
+pub mod fmod_backend;
+#[cfg(all(target_os = "linux", feature = "mpris"))]
+pub mod mpris;
 
 use std::io;
 use std::fs::File;
-use rfmod::Sys;
+use std::path::Path;
 use termion::raw::{ IntoRawMode };
 use termion::event::Key;
 use tui::Terminal;
@@ -37,18 +58,41 @@ use player::{ Player, MediaPlayer };
 use dir_select::DirSelect;
 use lyrics::LyricsGrabber;
 use spotify::SpotifyPlayer;
+use queue::QueueView;
+use history::HistoryView;
+use replaygain::ReplayGainMode;
+use state::Session;
+use control::{ Command, Request, Seek };
 
 pub const DIR_GUI_CODE:     usize = 444;
 pub const LOCAL_GUI_CODE:   usize = 0;
 pub const PANDORA_GUI_CODE: usize = 1;
 pub const SPOTIFY_GUI_CODE: usize = 2;
+pub const QUEUE_GUI_CODE:   usize = 3;
+pub const HISTORY_GUI_CODE: usize = 4;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Config {
     local_dir: String,
     pandora_username: String,
     pandora_password: String,
-    genius_token: String
+    genius_token: String,
+    #[serde(default)]
+    audio_backend: String,
+    #[serde(default)]
+    crossfade_ms: u32,
+    #[serde(default)]
+    replay_gain: ReplayGainMode,
+    #[serde(default)]
+    replay_gain_preamp_db: f32,
+    #[serde(default)]
+    seek_step_ms: u32,
+    #[serde(default)]
+    seek_step_large_ms: u32,
+    #[serde(default)]
+    resume_threshold_secs: u32,
+    #[serde(default)]
+    mpd_address: String
 }
 
 // Massive TODO: refactor to composition pattern
@@ -57,44 +101,129 @@ pub struct App<'a> {
     pandora_player: PandoraPlayer,
     local_player: LocalPlayer,
     media_player: MediaPlayer,
+    queue_view: QueueView,
+    history_view: HistoryView,
     dir_select: Option<DirSelect>,
-    config: Config,
-    fmod: Sys
+    config: Config
 }
 
 // hardcoded input/tick/draw calls because can't figure out how to return a trait object.. stuck in an inheritance mindset
 impl<'a> App<'a> {
-    fn new() -> App<'a> {
+    fn new() -> Result<App<'a>, failure::Error> {
         let config = App::read_config();
         //let spotify = SpotifyPlayer::new(config.clone());
-        let fmod = match rfmod::Sys::new() {
-            Ok(f) => f,
-            Err(e) => {
-                panic!("Error code : {:?}", e);
-            }
-        };
-
-        match fmod.init() {
-            rfmod::Status::Ok => {}
-            e => {
-                panic!("FmodSys.init failed : {:?}", e);
-            }
+        // without a login the pandora tab just has no stations, local playback doesn't need one
+        let (pandora_player, pandora_error) = match PandoraPlayer::new(config.clone()) {
+            Ok(pandora_player) => (pandora_player, None),
+            Err(e) => (PandoraPlayer::with_session(config.clone(), None, Vec::new()), Some(e.to_string()))
         };
-
-        App {
-            tabs: TabsState::new(vec!["Local", "Pandora", "Spotify"]),
-            pandora_player: PandoraPlayer::new(config.clone()),
+        let mut app = App {
+            tabs: TabsState::new(vec!["Local", "Pandora", "Spotify", "Queue", "History"]),
+            pandora_player: pandora_player,
             local_player: LocalPlayer::new(config.clone()),
-            media_player: MediaPlayer::new(config.clone()),
+            media_player: MediaPlayer::new(config.clone())?,
+            queue_view: QueueView::new(),
+            history_view: HistoryView::new(),
             dir_select: Some(DirSelect::new()),
             config: config,
-            fmod: fmod,
+        };
+        app.media_player.error = pandora_error;
+        app.restore_session();
+        Ok(app)
+    }
+
+    fn restore_session(&mut self) {
+        let session = match self.media_player.session() {
+            Some(session) => session.clone(),
+            None => return
+        };
+        self.media_player.play_mode = session.play_mode;
+        if !session.local_dir.is_empty() && session.local_dir != self.config.local_dir && Path::new(&session.local_dir).is_dir() {
+            self.rebuild_local_with_dir(&session.local_dir);
+        }
+        for song in session.queue {
+            self.media_player.queue.push(song);
+        }
+        if let Some(song) = session.playing.filter(|song| Path::new(&song.path).exists()) {
+            self.media_player.restore(song, session.position);
+        }
+        self.local_player.restore(session.selected_song.as_ref().map(String::as_str), &self.media_player);
+        if let Some(station) = &session.station {
+            self.pandora_player.restore_station(station);
         }
+        if session.tab < self.tabs.titles.len() {
+            self.tabs.index = session.tab;
+        }
+    }
+
+    // pandora tracks aren't kept, their stream urls will have expired by the next start
+    fn save_session(&mut self) {
+        self.media_player.remember_position();
+        let playing = self.media_player.now_playing.clone().filter(|song| !song.is_stream());
+        let session = Session {
+            tab: if self.tabs.index == DIR_GUI_CODE { LOCAL_GUI_CODE } else { self.tabs.index },
+            local_dir: self.config.local_dir.clone(),
+            selected_song: self.local_player.selected_path(),
+            position: if playing.is_some() { self.media_player.position().unwrap_or(0) } else { 0 },
+            playing: playing,
+            queue: self.media_player.queue.songs().to_vec(),
+            play_mode: self.media_player.play_mode,
+            station: self.pandora_player.station_name(),
+        };
+        self.media_player.save_session(session);
+    }
+
+    // answers a control socket request, false once it asked muscli to quit
+    fn answer(&mut self, request: Request) -> bool {
+        let quit = request.command == Command::Quit;
+        let result = if quit { Ok(None) } else { self.command(request.command) };
+        let _ = request.reply.send(control::response(result));
+        !quit
+    }
+
+    fn command(&mut self, command: Command) -> Result<Option<String>, String> {
+        // pandora tracks are the only streams, anything else belongs to the local player
+        let pandora = self.media_player.now_playing.as_ref().map_or(false, |song| song.is_stream());
+        let playing = self.media_player.now_playing.is_some();
+        match command {
+            Command::Play if !playing => match self.media_player.queue.pop_next() {
+                Some(song) => self.media_player.play_song(song),
+                None => return Err(String::from("nothing to play"))
+            },
+            Command::Play => { if self.media_player.paused() { self.media_player.toggle_pause(); } }
+            Command::Pause => { if playing && !self.media_player.paused() { self.media_player.toggle_pause(); } }
+            Command::Toggle if !playing => return Err(String::from("nothing is playing")),
+            Command::Toggle => self.media_player.toggle_pause(),
+            Command::Stop if pandora => self.pandora_player.stop(&mut self.media_player),
+            Command::Stop => self.local_player.stop(&mut self.media_player),
+            Command::Next if pandora => self.pandora_player.next(&mut self.media_player),
+            Command::Next => self.local_player.next(&mut self.media_player),
+            Command::Previous if pandora => self.pandora_player.previous(&mut self.media_player),
+            Command::Previous => self.local_player.previous(&mut self.media_player),
+            Command::Seek(_) if !playing => return Err(String::from("nothing is playing")),
+            Command::Seek(Seek::To(ms)) => self.media_player.set_position(ms),
+            Command::Seek(Seek::Forward(ms)) => self.media_player.seek_forward(ms),
+            Command::Seek(Seek::Backward(ms)) => self.media_player.seek_backward(ms),
+            Command::Volume(percent) => self.media_player.set_volume(percent as f32 / 100.0),
+            Command::Mode(mode) => self.local_player.set_play_mode(mode, &mut self.media_player),
+            Command::Rate(_) if !playing => return Err(String::from("nothing is playing")),
+            Command::Rate(rate) => self.media_player.set_rate(rate),
+            Command::Status => return serde_json::to_string(&control::status(&self.media_player)).map(Some).map_err(|e| e.to_string()),
+            Command::Queue => return serde_json::to_string(self.media_player.queue.songs()).map(Some).map_err(|e| e.to_string()),
+            Command::Enqueue(path) => {
+                let path = Path::new(&path);
+                if !library::is_audio_file(path) { return Err(format!("{} isn't a supported audio file", path.display())); }
+                let song = library::read_song(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                self.media_player.queue.push(song);
+            }
+            Command::Quit => {}
+        }
+        Ok(None)
     }
 
     pub fn rebuild_local_with_dir(&mut self, path: &String) {
         self.config.local_dir = path.clone();
-        self.local_player = LocalPlayer::new(self.config.clone());
+        self.local_player.set_directory(path, self.media_player.play_mode);
         self.tabs.index = LOCAL_GUI_CODE;
     }
     
@@ -107,11 +236,26 @@ impl<'a> App<'a> {
         config
     }
 
+    // true while the active view is collecting typed text, so global keys like q must pass through
+    fn capturing_input(&self) -> bool {
+        if self.media_player.capturing_input() { return true; }
+        match self.tabs.index {
+            LOCAL_GUI_CODE => self.local_player.capturing_input(),
+            PANDORA_GUI_CODE => self.pandora_player.capturing_input(),
+            DIR_GUI_CODE => true,
+            _ => false
+        }
+    }
+
     fn input(&mut self, key: Key) {
+        // the go to prompt makes capturing_input true, but its keys still belong to the media player
+        if (self.media_player.capturing_input() || !self.capturing_input()) && self.media_player.input(key) { return; }
         match self.tabs.index {
-            LOCAL_GUI_CODE => { self.local_player.input(key, &self.fmod, &mut self.media_player); }
-            PANDORA_GUI_CODE => { self.pandora_player.input(key, &self.fmod, &mut self.media_player); }
+            LOCAL_GUI_CODE => { self.local_player.input(key, &mut self.media_player); }
+            PANDORA_GUI_CODE => { self.pandora_player.input(key, &mut self.media_player); }
             SPOTIFY_GUI_CODE => {}
+            QUEUE_GUI_CODE => { self.queue_view.input(key, &mut self.media_player); }
+            HISTORY_GUI_CODE => { self.history_view.input(key, &mut self.media_player); }
             DIR_GUI_CODE => { 
                 let mut dir_select = self.dir_select.clone().unwrap();
                 dir_select.input(key, self);
@@ -122,10 +266,13 @@ impl<'a> App<'a> {
     }
 
     fn tick(&mut self) {
+        self.media_player.tick();
         match self.tabs.index {
-            LOCAL_GUI_CODE => { self.local_player.tick(&self.fmod, &mut self.media_player); }
-            PANDORA_GUI_CODE => { self.pandora_player.tick(&self.fmod, &mut self.media_player); }         
+            LOCAL_GUI_CODE => { self.local_player.tick(&mut self.media_player); }
+            PANDORA_GUI_CODE => { self.pandora_player.tick(&mut self.media_player); }         
             SPOTIFY_GUI_CODE => {}
+            QUEUE_GUI_CODE => { self.queue_view.tick(&mut self.media_player); }
+            HISTORY_GUI_CODE => { self.history_view.tick(&mut self.media_player); }
             DIR_GUI_CODE => { self.dir_select.as_mut().unwrap().tick(); }
             _ => {}    
         }
@@ -136,15 +283,50 @@ impl<'a> App<'a> {
             LOCAL_GUI_CODE => { self.local_player.draw(f, chunk, &mut self.media_player); }
             PANDORA_GUI_CODE => { self.pandora_player.draw(f, chunk, &mut self.media_player); }
             SPOTIFY_GUI_CODE => {}
+            QUEUE_GUI_CODE => { self.queue_view.draw(f, chunk, &mut self.media_player); }
+            HISTORY_GUI_CODE => { self.history_view.draw(f, chunk, &mut self.media_player); }
             DIR_GUI_CODE => { self.dir_select.as_mut().unwrap().draw(f, chunk); }
             _ => {}    
         }
     }
 }
 
-fn main() -> Result<(), failure::Error> {
-    let mut app = App::new();
+// media keys and desktop widgets, when there's a session bus to offer them on
+#[cfg(all(target_os = "linux", feature = "mpris"))]
+fn start_mpris(events: &Events) {
+    let _ = mpris::start(None, events.sender());
+}
+
+#[cfg(not(all(target_os = "linux", feature = "mpris")))]
+fn start_mpris(_events: &Events) {}
+
+fn main() {
+    // printed plainly rather than through the Debug output returning the error from main would give
+    if let Err(e) = run() {
+        eprintln!("muscli: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), failure::Error> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("scan-loudness") => return loudness::run(App::read_config(), &args[1..]),
+        Some("--daemon") => return run_daemon(),
+        Some(name) if control::SUBCOMMANDS.contains(&name) => return control::run(&args),
+        _ => {}
+    }
+
+    let mut app = App::new()?;
     let events = Events::new();
+    // a running daemon already owns the socket, the TUI then just isn't remote controllable
+    let socket = control::socket_path().filter(|path| control::listen(path, events.sender()).is_ok());
+    start_mpris(&events);
+    if !app.config.mpd_address.is_empty() {
+        if let Err(e) = mpd::listen(&app.config.mpd_address, events.sender()) {
+            app.media_player.error = Some(format!("MPD server on {} failed: {}", app.config.mpd_address, e));
+        }
+    }
     let stdout = io::stdout().into_raw_mode()?;
     let backend = TermionBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
@@ -156,19 +338,21 @@ fn main() -> Result<(), failure::Error> {
                 Key::Right => app.tabs.next(),
                 Key::Left => app.tabs.previous(),
                 Key::Char('d') => {
-                    if app.tabs.index == LOCAL_GUI_CODE {
+                    if app.tabs.index == LOCAL_GUI_CODE && !app.capturing_input() {
                         app.tabs.index = DIR_GUI_CODE;
                     } else {
                         app.input(input);
                     }
                 }
                 Key::Char('q') => { 
-                    if app.tabs.index != DIR_GUI_CODE { break; }
+                    if !app.capturing_input() { break; }
                     else { app.input(input); }
                 }
                 _ => app.input(input)
             }
-            Event::Tick => app.tick()
+            Event::Tick => app.tick(),
+            Event::Command(request) => { if !app.answer(request) { break; } }
+            Event::Mpd(request) => mpd::answer(&mut app, request)
         }
 
         terminal.draw(|mut f| {
@@ -177,7 +361,10 @@ fn main() -> Result<(), failure::Error> {
                 .constraints(vec![Constraint::Percentage(15), Constraint::Percentage(85)])
                 .split(f.size());
             Tabs::default()
-                .block(Block::default().borders(Borders::ALL).title("Interface"))
+                .block(Block::default().borders(Borders::ALL).title(match &app.media_player.error {
+                    Some(error) => error,
+                    None => "Interface"
+                }))
                 .titles(&app.tabs.titles)
                 .select(app.tabs.index)
                 .style(Style::default().modifier(Modifier::ITALIC))
@@ -186,5 +373,34 @@ fn main() -> Result<(), failure::Error> {
             app.draw(&mut f, root_chunks[1]);
         })?;
     }
+    if let Some(path) = &socket { control::unlisten(path); }
+    app.save_session();
+    Ok(())
+}
+
+fn run_daemon() -> Result<(), failure::Error> {
+    let mut app = App::new()?;
+    // stations are picked interactively, so without a terminal only the local player drives playback
+    app.tabs.index = LOCAL_GUI_CODE;
+    if let Some(error) = app.media_player.error.take() {
+        eprintln!("muscli: {}", error);
+    }
+    let events = Events::headless();
+    let socket = control::socket_path().ok_or_else(|| failure::err_msg("no directory for the control socket"))?;
+    control::listen(&socket, events.sender())?;
+    start_mpris(&events);
+    if !app.config.mpd_address.is_empty() {
+        mpd::listen(&app.config.mpd_address, events.sender())?;
+    }
+    loop {
+        match events.next()? {
+            Event::Tick => app.tick(),
+            Event::Command(request) => { if !app.answer(request) { break; } }
+            Event::Mpd(request) => mpd::answer(&mut app, request),
+            Event::Input(_) => {}
+        }
+    }
+    control::unlisten(&socket);
+    app.save_session();
     Ok(())
 }
\ No newline at end of file
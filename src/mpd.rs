@@ -0,0 +1,572 @@
+use std::cmp;
+use std::collections::{ BTreeSet, HashMap };
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{ Hash, Hasher };
+use std::io::{ self, BufRead, BufReader, Write };
+use std::net::{ TcpListener, TcpStream };
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use termion::event::Key;
+use super::App;
+use super::control::{ self, Command, Seek };
+use super::event::Event;
+use super::order::PlayMode;
+use super::player::{ MediaPlayer, Song };
+
+const GREETING: &str = "OK MPD 0.19.0";
+// how often an idling client's view of the player is compared against the real one
+const IDLE_POLL_MS: u64 = 250;
+
+// error codes mpd clients expect in ACK lines
+const ACK_ERROR_ARG: u32 = 2;
+const ACK_ERROR_UNKNOWN: u32 = 5;
+const ACK_ERROR_NO_EXIST: u32 = 50;
+const ACK_ERROR_PLAYER_SYNC: u32 = 55;
+
+// answered by serve itself, since they change the connection rather than the player
+const SESSION_COMMANDS: [&str; 6] = ["close", "command_list_begin", "command_list_end", "command_list_ok_begin", "idle", "noidle"];
+// everything execute answers; anything else is unknown to it, so a new command has to be listed here to work
+const PLAYER_COMMANDS: [&str; 26] = ["add", "clear", "commands", "currentsong", "delete", "find", "list", "next", "notcommands", "outputs",
+    "pause", "ping", "play", "playid", "playlistinfo", "plchanges", "previous", "search", "seek", "seekcur", "seekid", "setvol", "stats",
+    "status", "stop", "tagtypes"];
+const TAG_TYPES: [&str; 6] = ["Artist", "Album", "Title", "Track", "Genre", "Date"];
+const SUBSYSTEMS: [&str; 4] = ["player", "playlist", "mixer", "options"];
+
+/// A command, or a whole command list, for the main loop to run against the player.
+pub struct Request {
+    commands: Vec<Vec<String>>,
+    list_ok: bool,
+    reply: mpsc::Sender<String>,
+}
+
+// answered as "ACK [code@index] {command} message"
+#[derive(Debug)]
+struct Ack {
+    code: u32,
+    message: String,
+}
+
+fn ack(code: u32, message: &str) -> Ack {
+    Ack { code: code, message: String::from(message) }
+}
+
+/// Serves the MPD protocol on `address` (e.g. "127.0.0.1:6600"), passing commands to the main loop through `tx`.
+pub fn listen(address: &str, tx: mpsc::Sender<Event<Key>>) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Ok(stream) = stream {
+                let tx = tx.clone();
+                thread::spawn(move || serve(stream, tx));
+            }
+        }
+    });
+    Ok(())
+}
+
+fn serve(stream: TcpStream, tx: mpsc::Sender<Event<Key>>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    writeln!(writer, "{}", GREETING)?;
+    let mut reader = BufReader::new(stream);
+    // commands collected between command_list_begin and command_list_end, and whether each gets a list_OK
+    let mut list: Option<(Vec<Vec<String>>, bool)> = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 { return Ok(()); }
+        let args = match split_args(line.trim_end()) {
+            Ok(ref args) if args.is_empty() => continue,
+            Ok(args) => args,
+            Err(reason) => { writeln!(writer, "ACK [{}@0] {{}} {}", ACK_ERROR_ARG, reason)?; continue; }
+        };
+        let name = args[0].clone();
+        match (name.as_str(), list.as_mut()) {
+            ("command_list_end", Some(_)) => {
+                let (commands, list_ok) = list.take().unwrap_or_default();
+                writer.write_all(run(&tx, commands, list_ok)?.as_bytes())?;
+            }
+            (_, Some((commands, _))) => commands.push(args),
+            ("command_list_begin", None) => list = Some((Vec::new(), false)),
+            ("command_list_ok_begin", None) => list = Some((Vec::new(), true)),
+            ("close", None) => return Ok(()),
+            ("idle", None) => writer.write_all(idle(&mut reader, &tx, &args[1..])?.as_bytes())?,
+            // the idle it was meant to end has already answered
+            ("noidle", None) => {}
+            _ => writer.write_all(run(&tx, vec![args], false)?.as_bytes())?
+        }
+    }
+}
+
+fn run(tx: &mpsc::Sender<Event<Key>>, commands: Vec<Vec<String>>, list_ok: bool) -> io::Result<String> {
+    let (reply_tx, reply_rx) = mpsc::channel();
+    let gone = || io::Error::new(io::ErrorKind::BrokenPipe, "muscli is shutting down");
+    tx.send(Event::Mpd(Request { commands: commands, list_ok: list_ok, reply: reply_tx })).map_err(|_| gone())?;
+    reply_rx.recv().map_err(|_| gone())
+}
+
+// muscli has no change notifications, so idle compares status snapshots until something differs or noidle arrives
+fn idle(reader: &mut BufReader<TcpStream>, tx: &mpsc::Sender<Event<Key>>, wanted: &[String]) -> io::Result<String> {
+    let status = || run(tx, vec![vec![String::from("status")]], false);
+    let before = status()?;
+    reader.get_ref().set_read_timeout(Some(Duration::from_millis(IDLE_POLL_MS)))?;
+    let mut line = String::new();
+    let result = loop {
+        match reader.read_line(&mut line) {
+            Ok(0) => break Err(io::Error::new(io::ErrorKind::UnexpectedEof, "client went away while idle")),
+            Ok(_) => break Ok(String::from("OK\n")),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {}
+            Err(e) => break Err(e)
+        }
+        let changed: Vec<&str> = match status() {
+            Ok(now) => changed_subsystems(&before, &now).into_iter().filter(|s| wanted.is_empty() || wanted.iter().any(|w| w == s)).collect(),
+            Err(e) => break Err(e)
+        };
+        if !changed.is_empty() {
+            break Ok(changed.iter().map(|s| format!("changed: {}\n", s)).collect::<String>() + "OK\n");
+        }
+    };
+    reader.get_ref().set_read_timeout(None)?;
+    result
+}
+
+fn changed_subsystems(before: &str, now: &str) -> Vec<&'static str> {
+    let fields = |text: &str| -> HashMap<String, String> {
+        text.lines().filter_map(|line| line.find(": ").map(|idx| (String::from(&line[..idx]), String::from(&line[idx + 2..])))).collect()
+    };
+    let (before, now) = (fields(before), fields(now));
+    let differs = |keys: &[&str]| keys.iter().any(|key| before.get(*key) != now.get(*key));
+    let keys: [&[&str]; 4] = [&["state", "songid"], &["playlist"], &["volume"], &["repeat", "random", "single"]];
+    SUBSYSTEMS.iter().zip(keys.iter()).filter(|(_, keys)| differs(keys)).map(|(subsystem, _)| *subsystem).collect()
+}
+
+/// Runs a request's commands in order, stopping at the first error like mpd does.
+pub fn answer(app: &mut App, request: Request) {
+    let mut response = String::new();
+    for (idx, args) in request.commands.iter().enumerate() {
+        match execute(app, &args[0], &args[1..]) {
+            Ok(body) => {
+                response.push_str(&body);
+                if request.list_ok { response.push_str("list_OK\n"); }
+            }
+            Err(ack) => {
+                response.push_str(&format!("ACK [{}@{}] {{{}}} {}\n", ack.code, idx, args[0], ack.message));
+                let _ = request.reply.send(response);
+                return;
+            }
+        }
+    }
+    response.push_str("OK\n");
+    let _ = request.reply.send(response);
+}
+
+fn execute(app: &mut App, name: &str, args: &[String]) -> Result<String, Ack> {
+    if !PLAYER_COMMANDS.contains(&name) { return Err(unknown_command(name)); }
+    let local_dir = app.config.local_dir.clone();
+    // queue entries come after the playing song in the playlist, if there is one
+    let offset = if app.media_player.now_playing.is_some() { 1 } else { 0 };
+    match name {
+        "ping" => Ok(String::new()),
+        "commands" => Ok(commands().iter().map(|c| format!("command: {}\n", c)).collect()),
+        "notcommands" => Ok(String::new()),
+        "tagtypes" => Ok(TAG_TYPES.iter().map(|t| format!("tagtype: {}\n", t)).collect()),
+        "outputs" => Ok(String::from("outputid: 0\noutputname: muscli\noutputenabled: 1\n")),
+        "stats" => Ok(stats(app.local_player.library_songs())),
+        "status" => Ok(status(&app.media_player)),
+        "currentsong" => Ok(app.media_player.now_playing.as_ref().map(|song| song_lines(song, &local_dir, 0)).unwrap_or_default()),
+        "playlistinfo" | "plchanges" => Ok(playlist(&app.media_player).iter().enumerate().map(|(pos, song)| song_lines(song, &local_dir, pos)).collect()),
+        "play" | "playid" => match args.first() {
+            None => control_command(app, Command::Play),
+            Some(pos) => {
+                match parse_number(pos)? as usize {
+                    pos if pos < offset => control_command(app, Command::Play),
+                    pos => match app.media_player.queue.remove(pos - offset) {
+                        Some(song) => { app.media_player.play_song(song); Ok(String::new()) }
+                        None => Err(ack(ACK_ERROR_ARG, "Bad song index"))
+                    }
+                }
+            }
+        },
+        "pause" => match args.first().map(String::as_str) {
+            Some("1") => control_command(app, Command::Pause),
+            Some("0") => control_command(app, Command::Play),
+            _ => control_command(app, Command::Toggle)
+        },
+        "stop" => control_command(app, Command::Stop),
+        "next" => control_command(app, Command::Next),
+        "previous" => control_command(app, Command::Previous),
+        // only the playing song (position and id 0) can be seeked
+        "seek" | "seekid" => match args {
+            [pos, time] if parse_number(pos)? == 0 => control_command(app, Command::Seek(Seek::To(parse_seconds(time)?))),
+            [_, _] => Err(ack(ACK_ERROR_ARG, "only the current song can be seeked")),
+            _ => Err(ack(ACK_ERROR_ARG, "wrong number of arguments"))
+        },
+        "seekcur" => match args.first().map(String::as_str) {
+            Some(time) if time.starts_with('+') => control_command(app, Command::Seek(Seek::Forward(parse_seconds(&time[1..])?))),
+            Some(time) if time.starts_with('-') => control_command(app, Command::Seek(Seek::Backward(parse_seconds(&time[1..])?))),
+            Some(time) => control_command(app, Command::Seek(Seek::To(parse_seconds(time)?))),
+            None => Err(ack(ACK_ERROR_ARG, "wrong number of arguments"))
+        },
+        "setvol" => match args.first() {
+            Some(volume) => control_command(app, Command::Volume(cmp::min(parse_number(volume)?, 100))),
+            None => Err(ack(ACK_ERROR_ARG, "wrong number of arguments"))
+        },
+        "add" => match args.first() {
+            Some(uri) => {
+                let songs = songs_under(app.local_player.library_songs(), &local_dir, uri);
+                if songs.is_empty() { return Err(ack(ACK_ERROR_NO_EXIST, "No such directory")); }
+                for song in songs { app.media_player.queue.push(song); }
+                Ok(String::new())
+            }
+            None => Err(ack(ACK_ERROR_ARG, "wrong number of arguments"))
+        },
+        "clear" => {
+            app.media_player.queue.clear();
+            control_command(app, Command::Stop)
+        }
+        "delete" => match args.first() {
+            Some(pos) => match parse_number(pos)? as usize {
+                pos if pos < offset => Err(ack(ACK_ERROR_ARG, "the playing song can't be removed")),
+                pos => match app.media_player.queue.remove(pos - offset) {
+                    Some(_) => Ok(String::new()),
+                    None => Err(ack(ACK_ERROR_ARG, "Bad song index"))
+                }
+            },
+            None => Err(ack(ACK_ERROR_ARG, "wrong number of arguments"))
+        },
+        "find" | "search" => {
+            let filters = parse_filters(args)?;
+            let songs = app.local_player.library_songs();
+            Ok(songs.iter().filter(|song| matches(song, &local_dir, &filters, name == "find")).map(|song| song_lines(song, &local_dir, usize::max_value())).collect())
+        }
+        "list" => list(app.local_player.library_songs(), &local_dir, args),
+        _ => Err(unknown_command(name))
+    }
+}
+
+fn unknown_command(name: &str) -> Ack {
+    ack(ACK_ERROR_UNKNOWN, &format!("unknown command \"{}\"", name))
+}
+
+fn commands() -> Vec<&'static str> {
+    let mut names: Vec<&str> = SESSION_COMMANDS.iter().chain(PLAYER_COMMANDS.iter()).cloned().collect();
+    names.sort();
+    names
+}
+
+fn control_command(app: &mut App, command: Command) -> Result<String, Ack> {
+    app.command(command).map(|_| String::new()).map_err(|reason| ack(ACK_ERROR_PLAYER_SYNC, &reason))
+}
+
+fn parse_number(text: &str) -> Result<u32, Ack> {
+    text.parse().map_err(|_| ack(ACK_ERROR_ARG, &format!("Integer expected: {}", text)))
+}
+
+// mpd seeks take fractional seconds
+fn parse_seconds(text: &str) -> Result<u32, Ack> {
+    match text.parse::<f64>() {
+        Ok(seconds) if seconds >= 0.0 => Ok((seconds * 1000.0) as u32),
+        _ => Err(ack(ACK_ERROR_ARG, &format!("Number expected: {}", text)))
+    }
+}
+
+/// Splits a command line into its arguments, which may be double quoted with backslash escapes.
+fn split_args(line: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.peek().map_or(false, |c| c.is_whitespace()) { chars.next(); }
+        let quoted = match chars.peek() {
+            Some(&c) => c == '"',
+            None => return Ok(args)
+        };
+        let mut arg = String::new();
+        if quoted {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c) => arg.push(c),
+                        None => return Err(String::from("unterminated quoted argument"))
+                    },
+                    Some(c) => arg.push(c),
+                    None => return Err(String::from("unterminated quoted argument"))
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() { break; }
+                arg.push(c);
+                chars.next();
+            }
+        }
+        args.push(arg);
+    }
+}
+
+// the playing song and then the queue, which is what mpd clients call the playlist
+fn playlist(media_player: &MediaPlayer) -> Vec<Song> {
+    media_player.now_playing.iter().chain(media_player.queue.songs().iter()).cloned().collect()
+}
+
+fn playlist_version(songs: &[Song]) -> u32 {
+    let mut hasher = DefaultHasher::new();
+    for song in songs { song.path.hash(&mut hasher); }
+    hasher.finish() as u32
+}
+
+fn status(media_player: &MediaPlayer) -> String {
+    let status = control::status(media_player);
+    let songs = playlist(media_player);
+    let flag = |on: bool| if on { 1 } else { 0 };
+    let mut lines = format!("volume: {}\nrepeat: {}\nrandom: {}\nsingle: {}\nconsume: 0\nplaylist: {}\nplaylistlength: {}\nstate: {}\n",
+        if status.muted { 0 } else { (status.volume * 100.0).round() as u32 },
+        flag(status.play_mode == PlayMode::RepeatAll || status.play_mode == PlayMode::RepeatOne),
+        flag(status.play_mode == PlayMode::Shuffle || status.play_mode == PlayMode::AlbumShuffle),
+        flag(status.play_mode == PlayMode::RepeatOne || status.play_mode == PlayMode::StopAtEnd),
+        playlist_version(&songs), songs.len(),
+        match status.state { "playing" => "play", "paused" => "pause", _ => "stop" });
+    if status.song.is_some() {
+        lines.push_str(&format!("song: 0\nsongid: 0\ntime: {}:{}\nelapsed: {:.3}\nduration: {:.3}\n",
+            status.position_ms / 1000, status.length_ms / 1000, status.position_ms as f64 / 1000.0, status.length_ms as f64 / 1000.0));
+        if songs.len() > 1 { lines.push_str("nextsong: 1\nnextsongid: 1\n"); }
+    }
+    lines
+}
+
+fn stats(songs: &[Song]) -> String {
+    let artists: BTreeSet<&str> = songs.iter().map(|s| s.artist.as_str()).filter(|a| !a.is_empty()).collect();
+    let albums: BTreeSet<&str> = songs.iter().map(|s| s.album.as_str()).filter(|a| !a.is_empty()).collect();
+    format!("artists: {}\nalbums: {}\nsongs: {}\ndb_playtime: {}\n", artists.len(), albums.len(), songs.len(), songs.iter().map(|s| s.length as u64).sum::<u64>() / 1000)
+}
+
+// mpd names songs relative to its music directory, muscli's is local_dir
+fn uri(song: &Song, local_dir: &str) -> String {
+    match Path::new(&song.path).strip_prefix(local_dir) {
+        Ok(relative) if !local_dir.is_empty() => relative.to_string_lossy().into_owned(),
+        _ => song.path.clone()
+    }
+}
+
+// songs from find and search aren't in the playlist, they're passed usize::max_value() for pos
+fn song_lines(song: &Song, local_dir: &str, pos: usize) -> String {
+    let mut lines = format!("file: {}\n", uri(song, local_dir));
+    for &(key, value) in &[("Title", &song.name), ("Artist", &song.artist), ("Album", &song.album), ("Genre", &song.genre)] {
+        if !value.is_empty() { lines.push_str(&format!("{}: {}\n", key, value)); }
+    }
+    if song.year > 0 { lines.push_str(&format!("Date: {}\n", song.year)); }
+    if song.track > 0 { lines.push_str(&format!("Track: {}\n", song.track)); }
+    if song.length > 0 { lines.push_str(&format!("Time: {}\nduration: {:.3}\n", song.length / 1000, song.length as f64 / 1000.0)); }
+    if pos != usize::max_value() { lines.push_str(&format!("Pos: {}\nId: {}\n", pos, pos)); }
+    lines
+}
+
+// the song itself, or every song in the directory it names
+fn songs_under(songs: &[Song], local_dir: &str, target: &str) -> Vec<Song> {
+    let target = target.trim_matches('/');
+    songs.iter().filter(|song| {
+        let uri = uri(song, local_dir);
+        target.is_empty() || uri == target || uri.starts_with(&format!("{}/", target))
+    }).cloned().collect()
+}
+
+fn tag_values(song: &Song, local_dir: &str, tag: &str) -> Vec<String> {
+    match tag {
+        "artist" | "albumartist" => vec![song.artist.clone()],
+        "album" => vec![song.album.clone()],
+        "title" => vec![song.name.clone()],
+        "genre" => vec![song.genre.clone()],
+        "date" => vec![if song.year > 0 { song.year.to_string() } else { String::new() }],
+        "track" => vec![if song.track > 0 { song.track.to_string() } else { String::new() }],
+        "file" => vec![uri(song, local_dir)],
+        _ => vec![song.artist.clone(), song.album.clone(), song.name.clone(), song.genre.clone(), uri(song, local_dir)]
+    }
+}
+
+fn tag_key(tag: &str) -> &'static str {
+    match tag {
+        "artist" => "Artist",
+        "albumartist" => "AlbumArtist",
+        "album" => "Album",
+        "title" => "Title",
+        "genre" => "Genre",
+        "date" => "Date",
+        "track" => "Track",
+        _ => "file"
+    }
+}
+
+fn known_tag(tag: &str) -> Result<String, Ack> {
+    let tag = tag.to_lowercase();
+    match tag.as_str() {
+        "artist" | "albumartist" | "album" | "title" | "genre" | "date" | "track" | "file" | "any" => Ok(tag),
+        _ => Err(ack(ACK_ERROR_ARG, &format!("Unknown tag type: {}", tag)))
+    }
+}
+
+// find matches whole values, search matches case insensitive substrings
+fn matches(song: &Song, local_dir: &str, filters: &[(String, String)], exact: bool) -> bool {
+    filters.iter().all(|(tag, value)| {
+        let lowercase = value.to_lowercase();
+        tag_values(song, local_dir, tag).iter().any(|v| if exact { v == value } else { v.to_lowercase().contains(&lowercase) })
+    })
+}
+
+/// Tag/value pairs, either the old "artist X album Y" form or a filter expression like
+/// ((artist == "X") AND (album == "Y")).
+fn parse_filters(args: &[String]) -> Result<Vec<(String, String)>, Ack> {
+    if args.len() == 1 && args[0].starts_with('(') {
+        return parse_expression(&args[0]).ok_or_else(|| ack(ACK_ERROR_ARG, "unsupported filter expression"))
+            .and_then(|filters| filters.into_iter().map(|(tag, value)| known_tag(&tag).map(|tag| (tag, value))).collect());
+    }
+    if args.len() % 2 != 0 { return Err(ack(ACK_ERROR_ARG, "tag and value expected in pairs")); }
+    args.chunks(2).map(|pair| known_tag(&pair[0]).map(|tag| (tag, pair[1].clone()))).collect()
+}
+
+fn parse_expression(text: &str) -> Option<Vec<(String, String)>> {
+    let text = text.trim();
+    if !text.starts_with('(') || !text.ends_with(')') { return None; }
+    let inner = text[1..text.len() - 1].trim();
+    if !inner.starts_with('(') {
+        let space = inner.find(' ')?;
+        let rest = inner[space..].trim_start();
+        if !rest.starts_with("==") { return None; }
+        return Some(vec![(String::from(&inner[..space]), unquote(rest[2..].trim())?)]);
+    }
+
+    // several bracketed expressions joined with AND
+    let mut filters = Vec::new();
+    let (mut depth, mut start, mut quote, mut escaped) = (0, 0, None, false);
+    let mut between = String::new();
+    for (idx, c) in inner.char_indices() {
+        match (quote, c) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '(') => {
+                if depth == 0 {
+                    if !filters.is_empty() && between.trim() != "AND" { return None; }
+                    start = idx;
+                }
+                depth += 1;
+            }
+            (None, ')') => {
+                depth -= 1;
+                if depth == 0 {
+                    filters.extend(parse_expression(&inner[start..=idx])?);
+                    between.clear();
+                }
+            }
+            (None, c) if depth == 0 => between.push(c),
+            (None, _) => {}
+        }
+    }
+    if depth != 0 || !between.trim().is_empty() { return None; }
+    Some(filters)
+}
+
+fn unquote(text: &str) -> Option<String> {
+    let quote = text.chars().next().filter(|&c| c == '"' || c == '\'')?;
+    let mut value = String::new();
+    let mut chars = text[1..].chars();
+    loop {
+        match chars.next()? {
+            '\\' => value.push(chars.next()?),
+            c if c == quote => return if chars.as_str().is_empty() { Some(value) } else { None },
+            c => value.push(c)
+        }
+    }
+}
+
+// "list album artist X" is the old way of asking for one artist's albums, and group clauses are ignored
+fn list(songs: &[Song], local_dir: &str, args: &[String]) -> Result<String, Ack> {
+    let tag = known_tag(args.first().ok_or_else(|| ack(ACK_ERROR_ARG, "wrong number of arguments"))?)?;
+    let args: Vec<String> = args[1..].iter().take_while(|arg| arg.to_lowercase() != "group").cloned().collect();
+    let filters = if tag == "album" && args.len() == 1 && !args[0].starts_with('(') {
+        vec![(String::from("artist"), args[0].clone())]
+    } else {
+        parse_filters(&args)?
+    };
+    let values: BTreeSet<String> = songs.iter()
+        .filter(|song| matches(song, local_dir, &filters, true))
+        .flat_map(|song| tag_values(song, local_dir, &tag))
+        .filter(|value| !value.is_empty())
+        .collect();
+    Ok(values.iter().map(|value| format!("{}: {}\n", tag_key(&tag), value)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::Config;
+    use super::super::audio::testing::{ self, media_player };
+
+    fn song(path: &str, artist: &str, album: &str) -> Song {
+        Song { path: format!("/music/{}", path), artist: String::from(artist), album: String::from(album), length: 200000, ..testing::song(path) }
+    }
+
+    fn library() -> Vec<Song> {
+        vec![song("a/x.mp3", "Alpha", "First"), song("a/y.mp3", "Alpha", "Second"), song("b/z.mp3", "Beta Band", "Third")]
+    }
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| String::from(*a)).collect()
+    }
+
+    #[test]
+    fn arguments_split_on_spaces_and_quotes() {
+        assert_eq!(split_args(r#"find artist "Beta Band""#).unwrap(), strings(&["find", "artist", "Beta Band"]));
+        assert_eq!(split_args(r#"find "(Artist == \"X\")""#).unwrap(), strings(&["find", r#"(Artist == "X")"#]));
+        assert!(split_args(r#"add "unterminated"#).is_err());
+    }
+
+    #[test]
+    fn filters_find_and_list_the_library() {
+        let songs = library();
+        let pairs = parse_filters(&strings(&["Artist", "Alpha", "album", "Second"])).unwrap();
+        assert_eq!(pairs, vec![(String::from("artist"), String::from("Alpha")), (String::from("album"), String::from("Second"))]);
+        let expression = parse_filters(&strings(&[r#"((artist == "Alpha") AND (album == 'Se\'cond'))"#])).unwrap();
+        assert_eq!(expression[1], (String::from("album"), String::from("Se'cond")));
+        assert!(parse_filters(&strings(&["artist"])).is_err());
+        assert!(parse_filters(&strings(&["mood", "calm"])).is_err());
+
+        let found: Vec<&Song> = songs.iter().filter(|s| matches(s, "/music", &pairs, true)).collect();
+        assert_eq!(found.len(), 1);
+        let searched = parse_filters(&strings(&["any", "band"])).unwrap();
+        assert_eq!(songs.iter().filter(|s| matches(s, "/music", &searched, false)).count(), 1);
+
+        assert_eq!(list(&songs, "/music", &strings(&["artist"])).unwrap(), "Artist: Alpha\nArtist: Beta Band\n");
+        assert_eq!(list(&songs, "/music", &strings(&["album", "Alpha"])).unwrap(), "Album: First\nAlbum: Second\n");
+        assert_eq!(songs_under(&songs, "/music", "a").len(), 2);
+        assert_eq!(songs_under(&songs, "/music", "b/z.mp3").len(), 1);
+        assert!(songs_under(&songs, "/music", "c").is_empty());
+    }
+
+    #[test]
+    fn status_describes_the_playlist() {
+        let (mut media_player, clock) = media_player(Config::default(), &[("/music/a/x.mp3", 200000)]);
+        let stopped = status(&media_player);
+        assert!(stopped.contains("state: stop\n") && !stopped.contains("songid"));
+
+        let songs = library();
+        media_player.play_song(songs[0].clone());
+        media_player.queue.push(songs[2].clone());
+        clock.advance(61500);
+        let playing = status(&media_player);
+        assert!(playing.contains("state: play\nsong: 0\nsongid: 0\ntime: 61:200\nelapsed: 61.500\n"));
+        assert!(playing.contains("playlistlength: 2\n") && playing.contains("nextsong: 1\n"));
+        assert_eq!(changed_subsystems(&stopped, &playing), vec!["player", "playlist"]);
+        assert!(song_lines(&songs[0], "/music", 0).starts_with("file: a/x.mp3\nTitle: a/x.mp3\nArtist: Alpha\n"));
+    }
+
+    #[test]
+    fn commands_lists_everything_answered() {
+        let names = commands();
+        assert!(names.contains(&"stop") && names.contains(&"tagtypes") && names.contains(&"idle"));
+        assert!(names.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
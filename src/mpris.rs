@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{ Hash, Hasher };
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+use dbus::{ BusType, Connection, Message, NameFlag, SignalArgs };
+use dbus::arg::{ Arg, IterAppend, RefArg, Variant };
+use dbus::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged;
+use dbus::tree::{ Access, Factory, MethodErr, MTFn, Property };
+use serde_json::Value;
+use termion::event::Key;
+use super::control::{ self, Command, Seek };
+use super::event::Event;
+use super::order::PlayMode;
+use super::player::{ MIN_RATE, MAX_RATE };
+
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.muscli";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const ROOT_INTERFACE: &str = "org.mpris.MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+// how often the player is checked for changes to signal
+const POLL_MS: u32 = 250;
+// a position further than this from where playback should have got to counts as a seek
+const SEEK_TOLERANCE_MS: i64 = 1500;
+// looked for next to local songs, which carry no art url of their own
+const COVER_FILES: [&str; 4] = ["cover.jpg", "cover.png", "folder.jpg", "front.jpg"];
+
+type Metadata = HashMap<String, Variant<Box<dyn RefArg>>>;
+
+/// Offers the player to media keys and desktop widgets as org.mpris.MediaPlayer2.muscli, on the
+/// session bus or the bus at `address`. Controls are sent to the main loop like control socket commands.
+pub fn start(address: Option<String>, tx: mpsc::Sender<Event<Key>>) -> Result<(), failure::Error> {
+    // dbus connections can't move between threads, so it's opened on the one that serves it
+    let (ready_tx, ready_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let connection = match address {
+            // a bare address isn't registered with the bus yet, which get_private does for us
+            Some(address) => Connection::open_private(&address).and_then(|c| c.register().map(|_| c)),
+            None => Connection::get_private(BusType::Session)
+        };
+        let connection = match connection.and_then(|c| c.register_name(BUS_NAME, NameFlag::ReplaceExisting as u32).map(|_| c)) {
+            Ok(connection) => connection,
+            Err(e) => { let _ = ready_tx.send(Err(e.to_string())); return; }
+        };
+        if let Err(e) = register(&connection, &tx) {
+            let _ = ready_tx.send(Err(e.to_string()));
+            return;
+        }
+        let _ = ready_tx.send(Ok(()));
+        serve(&connection, &tx);
+    });
+    match ready_rx.recv() {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(reason)) => Err(failure::err_msg(format!("couldn't offer MPRIS on the session bus: {}", reason))),
+        Err(_) => Err(failure::err_msg("the MPRIS thread stopped before starting"))
+    }
+}
+
+fn status(tx: &mpsc::Sender<Event<Key>>) -> Result<Value, MethodErr> {
+    let body = control::request(tx, Command::Status).map_err(|e| MethodErr::failed(&e))?;
+    serde_json::from_str(&body).map_err(|e| MethodErr::failed(&e))
+}
+
+fn register(connection: &Connection, tx: &mpsc::Sender<Event<Key>>) -> Result<(), dbus::Error> {
+    let f = Factory::new_fn::<()>();
+    let command = |name: &'static str, command: Command| {
+        let tx = tx.clone();
+        f.method(name, (), move |m| {
+            control::request(&tx, command.clone()).map_err(|e| MethodErr::failed(&e))?;
+            Ok(vec![m.msg.method_return()])
+        })
+    };
+    let constant = |name: &'static str, value: bool| f.property::<bool, _>(name, ()).on_get(move |i, _| { i.append(value); Ok(()) });
+
+    let seek_tx = tx.clone();
+    let position_tx = tx.clone();
+    let volume_tx = tx.clone();
+    let loop_tx = tx.clone();
+    let shuffle_tx = tx.clone();
+    let rate_tx = tx.clone();
+    let root = f.interface(ROOT_INTERFACE, ())
+        .add_m(f.method("Raise", (), |m| Ok(vec![m.msg.method_return()])))
+        .add_m(command("Quit", Command::Quit))
+        .add_p(constant("CanQuit", true))
+        .add_p(constant("CanRaise", false))
+        .add_p(constant("HasTrackList", false))
+        .add_p(f.property::<&str, _>("Identity", ()).on_get(|i, _| { i.append("muscli"); Ok(()) }))
+        .add_p(f.property::<Vec<&str>, _>("SupportedUriSchemes", ()).on_get(|i, _| { i.append(Vec::<String>::new()); Ok(()) }))
+        .add_p(f.property::<Vec<&str>, _>("SupportedMimeTypes", ()).on_get(|i, _| { i.append(Vec::<String>::new()); Ok(()) }));
+    let player = f.interface(PLAYER_INTERFACE, ())
+        .add_m(command("Next", Command::Next))
+        .add_m(command("Previous", Command::Previous))
+        .add_m(command("Pause", Command::Pause))
+        .add_m(command("PlayPause", Command::Toggle))
+        .add_m(command("Stop", Command::Stop))
+        .add_m(command("Play", Command::Play))
+        .add_m(f.method("Seek", (), move |m| {
+            let offset_us: i64 = m.msg.read1()?;
+            let seek = if offset_us < 0 { Seek::Backward((-offset_us / 1000) as u32) } else { Seek::Forward((offset_us / 1000) as u32) };
+            control::request(&seek_tx, Command::Seek(seek)).map_err(|e| MethodErr::failed(&e))?;
+            Ok(vec![m.msg.method_return()])
+        }).inarg::<i64, _>("Offset"))
+        .add_m(f.method("SetPosition", (), move |m| {
+            let (track_id, position_us): (dbus::Path, i64) = m.msg.read2()?;
+            // the spec says to ignore requests meant for a track that's no longer playing
+            let status = status(&position_tx)?;
+            if &*track_id == track_id_of(&status).as_str() && position_us >= 0 {
+                control::request(&position_tx, Command::Seek(Seek::To((position_us / 1000) as u32))).map_err(|e| MethodErr::failed(&e))?;
+            }
+            Ok(vec![m.msg.method_return()])
+        }).inarg::<dbus::Path, _>("TrackId").inarg::<i64, _>("Position"))
+        .add_m(f.method("OpenUri", (), |_| Err(MethodErr::failed(&"muscli doesn't open uris"))).inarg::<&str, _>("Uri"))
+        .add_s(f.signal("Seeked", ()).sarg::<i64, _>("Position"))
+        .add_p(status_property::<&str, _>(&f, tx, "PlaybackStatus", |status, i| i.append(playback_status(status))))
+        .add_p(status_property::<&str, _>(&f, tx, "LoopStatus", |status, i| i.append(loop_status(status))).access(Access::ReadWrite)
+            .on_set(move |i, _| {
+                let loop_status: &str = i.read()?;
+                let mode = mode_for_loop_status(loop_status).ok_or_else(|| MethodErr::invalid_arg(&loop_status))?;
+                send(&loop_tx, Command::Mode(mode))
+            }))
+        .add_p(status_property::<bool, _>(&f, tx, "Shuffle", |status, i| i.append(shuffle(status))).access(Access::ReadWrite)
+            .on_set(move |i, _| {
+                let on: bool = i.read()?;
+                match mode_for_shuffle(on, &status(&shuffle_tx)?) {
+                    Some(mode) => send(&shuffle_tx, Command::Mode(mode)),
+                    None => Ok(())
+                }
+            }))
+        .add_p(status_property::<f64, _>(&f, tx, "Rate", |status, i| i.append(status["rate"].as_f64().unwrap_or(1.0))).access(Access::ReadWrite)
+            .on_set(move |i, _| {
+                let rate: f64 = i.read()?;
+                // the spec asks for a rate of 0 to act like Pause
+                if rate <= 0.0 { return send(&rate_tx, Command::Pause); }
+                send(&rate_tx, Command::Rate((rate as f32).max(MIN_RATE).min(MAX_RATE)))
+            }))
+        .add_p(status_property::<HashMap<&str, Variant<Box<dyn RefArg>>>, _>(&f, tx, "Metadata", |status, i| i.append(metadata(status))))
+        .add_p(status_property::<i64, _>(&f, tx, "Position", |status, i| i.append(status["position_ms"].as_i64().unwrap_or(0) * 1000)))
+        .add_p(f.property::<f64, _>("Volume", ()).access(Access::ReadWrite)
+            .on_get({ let tx = tx.clone(); move |i, _| { i.append(volume(&status(&tx)?)); Ok(()) } })
+            .on_set(move |i, _| {
+                let volume: f64 = i.read()?;
+                let percent = (volume.max(0.0).min(1.0) * 100.0).round() as u32;
+                send(&volume_tx, Command::Volume(percent))
+            }))
+        .add_p(f.property::<f64, _>("MinimumRate", ()).on_get(|i, _| { i.append(MIN_RATE as f64); Ok(()) }))
+        .add_p(f.property::<f64, _>("MaximumRate", ()).on_get(|i, _| { i.append(MAX_RATE as f64); Ok(()) }))
+        .add_p(constant("CanGoNext", true))
+        .add_p(constant("CanGoPrevious", true))
+        .add_p(constant("CanPlay", true))
+        .add_p(constant("CanPause", true))
+        .add_p(constant("CanSeek", true))
+        .add_p(constant("CanControl", true));
+
+    let tree = f.tree(()).add(f.object_path(OBJECT_PATH, ()).introspectable().add(root).add(player));
+    tree.set_registered(connection, true)?;
+    connection.add_handler(tree);
+    Ok(())
+}
+
+// for property writes, which have nothing to return
+fn send(tx: &mpsc::Sender<Event<Key>>, command: Command) -> Result<(), MethodErr> {
+    control::request(tx, command).map(|_| ()).map_err(|e| MethodErr::failed(&e))
+}
+
+fn status_property<A: Arg, F>(f: &Factory<MTFn<()>, ()>, tx: &mpsc::Sender<Event<Key>>, name: &'static str, get: F) -> Property<MTFn<()>, ()>
+    where F: Fn(&Value, &mut IterAppend) + 'static
+{
+    let tx = tx.clone();
+    f.property::<A, _>(name, ()).on_get(move |i, _| { get(&status(&tx)?, i); Ok(()) })
+}
+
+// there's no change notification to hook into, so the player is polled and differences become signals
+fn serve(connection: &Connection, tx: &mpsc::Sender<Event<Key>>) {
+    let path = dbus::Path::from(OBJECT_PATH);
+    let mut last: Option<(Value, Instant)> = None;
+    loop {
+        connection.incoming(POLL_MS).next();
+        if last.as_ref().map_or(false, |(_, at)| at.elapsed().as_millis() < POLL_MS as u128) { continue; }
+        let now = match status(tx) {
+            Ok(now) => now,
+            Err(_) => return
+        };
+        if let Some((before, at)) = &last {
+            let changed = changed_properties(before, &now);
+            if !changed.is_empty() {
+                let signal = PropertiesPropertiesChanged { interface_name: String::from(PLAYER_INTERFACE), changed_properties: changed, invalidated_properties: Vec::new() };
+                let _ = connection.send(signal.to_emit_message(&path));
+            }
+            if let Some(position_us) = seeked(before, &now, at.elapsed().as_millis() as i64) {
+                let _ = connection.send(Message::signal(&path, &PLAYER_INTERFACE.into(), &"Seeked".into()).append1(position_us));
+            }
+        }
+        last = Some((now, Instant::now()));
+    }
+}
+
+fn playback_status(status: &Value) -> &'static str {
+    match status["state"].as_str() {
+        Some("playing") => "Playing",
+        Some("paused") => "Paused",
+        _ => "Stopped"
+    }
+}
+
+fn loop_status(status: &Value) -> &'static str {
+    match status["play_mode"].as_str() {
+        Some("RepeatAll") => "Playlist",
+        Some("RepeatOne") => "Track",
+        _ => "None"
+    }
+}
+
+fn shuffle(status: &Value) -> bool {
+    match status["play_mode"].as_str() {
+        Some("Shuffle") | Some("AlbumShuffle") => true,
+        _ => false
+    }
+}
+
+// muscli has a single play mode, so setting LoopStatus also turns shuffle off
+fn mode_for_loop_status(loop_status: &str) -> Option<PlayMode> {
+    match loop_status {
+        "None" => Some(PlayMode::Sequential),
+        "Track" => Some(PlayMode::RepeatOne),
+        "Playlist" => Some(PlayMode::RepeatAll),
+        _ => None
+    }
+}
+
+// None when the mode already shuffles or doesn't, as asked
+fn mode_for_shuffle(on: bool, status: &Value) -> Option<PlayMode> {
+    match (on, shuffle(status)) {
+        (true, false) => Some(PlayMode::Shuffle),
+        (false, true) => Some(PlayMode::RepeatAll),
+        _ => None
+    }
+}
+
+fn volume(status: &Value) -> f64 {
+    if status["muted"].as_bool().unwrap_or(false) { 0.0 } else { status["volume"].as_f64().unwrap_or(0.0) }
+}
+
+fn track_id_of(status: &Value) -> String {
+    match status["song"]["path"].as_str() {
+        Some(path) => {
+            let mut hasher = DefaultHasher::new();
+            path.hash(&mut hasher);
+            format!("/org/muscli/track/{:016x}", hasher.finish())
+        }
+        None => String::from(NO_TRACK)
+    }
+}
+
+fn art_url(song: &Value) -> Option<String> {
+    if let Some(url) = song["art_url"].as_str().filter(|url| !url.is_empty()) { return Some(String::from(url)); }
+    let dir = Path::new(song["path"].as_str()?).parent()?;
+    COVER_FILES.iter().map(|name| dir.join(name)).find(|path| path.is_file()).map(|path| format!("file://{}", path.display()))
+}
+
+fn metadata(status: &Value) -> Metadata {
+    let mut metadata: Metadata = HashMap::new();
+    metadata.insert(String::from("mpris:trackid"), Variant(Box::new(dbus::Path::from(track_id_of(status)))));
+    let song = &status["song"];
+    if song.is_null() { return metadata; }
+    let text = |key: &str| String::from(song[key].as_str().unwrap_or(""));
+    metadata.insert(String::from("xesam:title"), Variant(Box::new(text("name"))));
+    metadata.insert(String::from("xesam:album"), Variant(Box::new(text("album"))));
+    if !text("artist").is_empty() {
+        metadata.insert(String::from("xesam:artist"), Variant(Box::new(vec![text("artist")])));
+    }
+    // pandora tracks don't know their length up front, the playing sound does
+    let length_ms = match song["length"].as_i64() { Some(ms) if ms > 0 => ms, _ => status["length_ms"].as_i64().unwrap_or(0) };
+    if length_ms > 0 {
+        metadata.insert(String::from("mpris:length"), Variant(Box::new(length_ms * 1000)));
+    }
+    if let Some(url) = art_url(song) {
+        metadata.insert(String::from("mpris:artUrl"), Variant(Box::new(url)));
+    }
+    metadata
+}
+
+fn changed_properties(before: &Value, now: &Value) -> Metadata {
+    let mut changed: Metadata = HashMap::new();
+    if playback_status(before) != playback_status(now) {
+        changed.insert(String::from("PlaybackStatus"), Variant(Box::new(String::from(playback_status(now)))));
+    }
+    if before["song"] != now["song"] || before["length_ms"] != now["length_ms"] {
+        changed.insert(String::from("Metadata"), Variant(Box::new(metadata(now))));
+    }
+    if volume(before) != volume(now) {
+        changed.insert(String::from("Volume"), Variant(Box::new(volume(now))));
+    }
+    if before["play_mode"] != now["play_mode"] {
+        changed.insert(String::from("LoopStatus"), Variant(Box::new(String::from(loop_status(now)))));
+        changed.insert(String::from("Shuffle"), Variant(Box::new(shuffle(now))));
+    }
+    if before["rate"] != now["rate"] {
+        changed.insert(String::from("Rate"), Variant(Box::new(now["rate"].as_f64().unwrap_or(1.0))));
+    }
+    changed
+}
+
+// the new position in microseconds, if it isn't where `elapsed_ms` of playback at the current rate would have taken it
+fn seeked(before: &Value, now: &Value, elapsed_ms: i64) -> Option<i64> {
+    if before["song"]["path"] != now["song"]["path"] || now["song"].is_null() { return None; }
+    let position = |status: &Value| status["position_ms"].as_i64().unwrap_or(0);
+    let expected = match before["state"].as_str() {
+        Some("playing") => position(before) + (elapsed_ms as f64 * before["rate"].as_f64().unwrap_or(1.0)) as i64,
+        _ => position(before)
+    };
+    let length = now["length_ms"].as_i64().unwrap_or(i64::max_value());
+    if (position(now) - expected.min(length)).abs() > SEEK_TOLERANCE_MS { Some(position(now) * 1000) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{ BufRead, BufReader };
+    use std::process::{ Command as Process, Stdio };
+
+    fn status_json(state: &str, path: &str, position_ms: u32) -> Value {
+        serde_json::from_str(&format!(r#"{{"state":"{}","song":{{"name":"Song","path":"{}","artist":"Band","album":"Record","length":200000,"art_url":""}},
+            "position_ms":{},"length_ms":200000,"volume":0.5,"muted":false,"rate":1.0,"play_mode":"RepeatOne","queue_length":0}}"#, state, path, position_ms)).unwrap()
+    }
+
+    #[test]
+    fn status_maps_to_properties() {
+        let playing = status_json("playing", "/music/a.mp3", 10000);
+        assert_eq!(playback_status(&playing), "Playing");
+        assert_eq!(loop_status(&playing), "Track");
+        let metadata = metadata(&playing);
+        assert!(metadata.contains_key("xesam:title") && metadata.contains_key("xesam:artist") && metadata.contains_key("mpris:length"));
+        assert!(!metadata.contains_key("mpris:artUrl"));
+        assert_eq!(track_id_of(&playing), track_id_of(&status_json("paused", "/music/a.mp3", 0)));
+        assert_eq!(track_id_of(&serde_json::from_str(r#"{"state":"stopped","song":null}"#).unwrap()), NO_TRACK);
+
+        let paused = status_json("paused", "/music/a.mp3", 10500);
+        let changed = changed_properties(&playing, &paused);
+        assert_eq!(changed.keys().collect::<Vec<_>>(), vec!["PlaybackStatus"]);
+        assert_eq!(changed_properties(&playing, &status_json("playing", "/music/b.mp3", 0)).len(), 1);
+    }
+
+    #[test]
+    fn loop_and_shuffle_writes_pick_a_play_mode() {
+        assert_eq!(mode_for_loop_status("Track"), Some(PlayMode::RepeatOne));
+        assert_eq!(mode_for_loop_status("None"), Some(PlayMode::Sequential));
+        assert_eq!(mode_for_loop_status("Sometimes"), None);
+        let repeating = status_json("playing", "/music/a.mp3", 0);
+        assert_eq!(mode_for_shuffle(true, &repeating), Some(PlayMode::Shuffle));
+        assert_eq!(mode_for_shuffle(false, &repeating), None);
+        let mut shuffling = repeating.clone();
+        shuffling["play_mode"] = Value::from("AlbumShuffle");
+        assert_eq!(mode_for_shuffle(true, &shuffling), None);
+        assert_eq!(mode_for_shuffle(false, &shuffling), Some(PlayMode::RepeatAll));
+    }
+
+    #[test]
+    fn jumps_in_position_are_seeks() {
+        let before = status_json("playing", "/music/a.mp3", 10000);
+        assert_eq!(seeked(&before, &status_json("playing", "/music/a.mp3", 10300), 250), None);
+        assert_eq!(seeked(&before, &status_json("playing", "/music/a.mp3", 40000), 250), Some(40000000));
+        assert_eq!(seeked(&before, &status_json("playing", "/music/b.mp3", 0), 250), None);
+    }
+
+    // run with `cargo test -- --ignored`, needs dbus-daemon installed
+    #[test]
+    #[ignore]
+    fn answers_on_a_private_session_bus() {
+        let mut daemon = Process::new("dbus-daemon").args(&["--session", "--nofork", "--print-address=1"]).stdout(Stdio::piped()).spawn().unwrap();
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+        let address = String::from(address.trim());
+
+        // stands in for the main loop
+        let (tx, rx) = mpsc::channel();
+        let (toggled_tx, toggled_rx) = mpsc::channel();
+        thread::spawn(move || {
+            while let Ok(Event::Command(request)) = rx.recv() {
+                let body = match request.command {
+                    Command::Status => Some(status_json("playing", "/music/a.mp3", 1000).to_string()),
+                    command => { let _ = toggled_tx.send(command); None }
+                };
+                let _ = request.reply.send(control::response(Ok(body)));
+            }
+        });
+        start(Some(address.clone()), tx).unwrap();
+
+        let client = Connection::open_private(&address).unwrap();
+        client.register().unwrap();
+        let get = Message::new_method_call(BUS_NAME, OBJECT_PATH, "org.freedesktop.DBus.Properties", "Get").unwrap()
+            .append2(PLAYER_INTERFACE, "PlaybackStatus");
+        let reply = client.send_with_reply_and_block(get, 2000).unwrap();
+        let Variant(playback): Variant<String> = reply.get1().unwrap();
+        assert_eq!(playback, "Playing");
+
+        let play_pause = Message::new_method_call(BUS_NAME, OBJECT_PATH, PLAYER_INTERFACE, "PlayPause").unwrap();
+        client.send_with_reply_and_block(play_pause, 2000).unwrap();
+        let set = Message::new_method_call(BUS_NAME, OBJECT_PATH, "org.freedesktop.DBus.Properties", "Set").unwrap()
+            .append3(PLAYER_INTERFACE, "LoopStatus", Variant("Playlist"));
+        client.send_with_reply_and_block(set, 2000).unwrap();
+        let _ = daemon.kill();
+        assert_eq!(toggled_rx.try_recv(), Ok(Command::Toggle));
+        assert_eq!(toggled_rx.try_recv(), Ok(Command::Mode(PlayMode::RepeatAll)));
+    }
+}
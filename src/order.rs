@@ -0,0 +1,145 @@
+use rand::thread_rng;
+use rand::seq::SliceRandom;
+use super::player::Song;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PlayMode {
+    Sequential,
+    RepeatAll,
+    RepeatOne,
+    StopAtEnd,
+    Shuffle,
+    AlbumShuffle,
+}
+
+impl Default for PlayMode {
+    fn default() -> PlayMode {
+        PlayMode::RepeatAll
+    }
+}
+
+impl PlayMode {
+    pub fn next(self) -> PlayMode {
+        match self {
+            PlayMode::Sequential => PlayMode::RepeatAll,
+            PlayMode::RepeatAll => PlayMode::RepeatOne,
+            PlayMode::RepeatOne => PlayMode::StopAtEnd,
+            PlayMode::StopAtEnd => PlayMode::Shuffle,
+            PlayMode::Shuffle => PlayMode::AlbumShuffle,
+            PlayMode::AlbumShuffle => PlayMode::Sequential,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            PlayMode::Sequential => "in order",
+            PlayMode::RepeatAll => "repeat all",
+            PlayMode::RepeatOne => "repeat one",
+            PlayMode::StopAtEnd => "stop after track",
+            PlayMode::Shuffle => "shuffle",
+            PlayMode::AlbumShuffle => "album shuffle",
+        }
+    }
+
+    /// Reads "repeat-all", "RepeatAll" and "repeatall" alike.
+    pub fn from_name(name: &str) -> Option<PlayMode> {
+        match name.replace('-', "").to_lowercase().as_str() {
+            "sequential" => Some(PlayMode::Sequential),
+            "repeatall" => Some(PlayMode::RepeatAll),
+            "repeatone" => Some(PlayMode::RepeatOne),
+            "stopatend" => Some(PlayMode::StopAtEnd),
+            "shuffle" => Some(PlayMode::Shuffle),
+            "albumshuffle" => Some(PlayMode::AlbumShuffle),
+            _ => None
+        }
+    }
+}
+
+/// The order a playlist is walked in. Shuffled orders are generated once and kept, so
+/// stepping back retraces exactly what was played instead of picking new random songs.
+pub struct PlayOrder {
+    order: Vec<usize>,
+    position: usize,
+}
+
+impl PlayOrder {
+    pub fn new(mode: PlayMode, songs: &[Song], current: Option<usize>) -> PlayOrder {
+        let mut order: Vec<usize> = (0..songs.len()).collect();
+        match mode {
+            PlayMode::Shuffle => order.shuffle(&mut thread_rng()),
+            PlayMode::AlbumShuffle => {
+                // albums are shuffled as blocks, tracks keep their playlist order within each album
+                let mut albums: Vec<Vec<usize>> = Vec::new();
+                for idx in order {
+                    match albums.iter_mut().find(|album| songs[album[0]].album == songs[idx].album) {
+                        Some(album) => album.push(idx),
+                        None => albums.push(vec![idx])
+                    }
+                }
+                albums.shuffle(&mut thread_rng());
+                order = albums.into_iter().flatten().collect();
+            }
+            _ => {}
+        }
+
+        let mut play_order = PlayOrder { order: order, position: 0 };
+        if let Some(current) = current {
+            if mode == PlayMode::Shuffle {
+                // start the shuffle from the song that's already playing
+                if let Some(pos) = play_order.order.iter().position(|&i| i == current) {
+                    play_order.order.swap(0, pos);
+                }
+            }
+            play_order.seek(current);
+        }
+        play_order
+    }
+
+    /// Moves to `current` in the order, e.g. after the user picked a song by hand.
+    pub fn seek(&mut self, current: usize) {
+        if let Some(pos) = self.order.iter().position(|&i| i == current) {
+            self.position = pos;
+        }
+    }
+
+    /// The playlist index to play after `current` finishes, or None to stop.
+    pub fn next(&mut self, mode: PlayMode, songs: &[Song], current: usize) -> Option<usize> {
+        if self.order.is_empty() { return None; }
+        self.seek(current);
+        match mode {
+            PlayMode::RepeatOne => Some(current),
+            PlayMode::StopAtEnd => None,
+            _ if self.position + 1 < self.order.len() => {
+                self.position += 1;
+                Some(self.order[self.position])
+            }
+            PlayMode::RepeatAll => {
+                self.position = 0;
+                Some(self.order[0])
+            }
+            PlayMode::Shuffle | PlayMode::AlbumShuffle => {
+                // every song has had its turn, deal a fresh order
+                *self = PlayOrder::new(mode, songs, None);
+                self.order.first().cloned()
+            }
+            PlayMode::Sequential => None,
+        }
+    }
+
+    pub fn previous(&mut self, mode: PlayMode, current: usize) -> Option<usize> {
+        if self.order.is_empty() { return None; }
+        self.seek(current);
+        match mode {
+            PlayMode::RepeatOne => Some(current),
+            _ if self.position > 0 => {
+                self.position -= 1;
+                Some(self.order[self.position])
+            }
+            PlayMode::RepeatAll => {
+                self.position = self.order.len() - 1;
+                Some(self.order[self.position])
+            }
+            _ => None
+        }
+    }
+}
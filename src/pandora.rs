@@ -2,7 +2,6 @@ use pandora_rs2::Pandora;
 use pandora_rs2::stations::{ ToStationToken, Station };
 use pandora_rs2::playlist::{ ToTrackToken, RateTrackRequest, Track };
 use pandora_rs2::method::Method;
-use rfmod::Sys;
 use termion::event::Key;
 use tui::backend::Backend;
 use tui::layout::{ Rect, Layout, Constraint, Direction };
@@ -11,10 +10,14 @@ use tui::terminal::Frame;
 use tui::style::{ Color, Style};
 use super::player::Player;
 use super::{ Config, MediaPlayer };
+use super::player::Song;
+use super::order::PlayMode;
+use super::search::Search;
+use super::replaygain::{ self, ReplayGain };
 
 pub struct PandoraPlayer {
     config: Config,
-    handle: Pandora,
+    handle: Option<Pandora>,
     stations: Vec<Station>,
     stations_names: Vec<String>,
     selected_idx: Option<usize>,
@@ -23,18 +26,28 @@ pub struct PandoraPlayer {
     rebuild_station_list: bool,
     current_playlist: Option<Vec<Track>>,
     current_playlist_titles: Option<Vec<String>>,
+    preloaded_idx: Option<usize>,
+    search: Search,
 }
 
 impl PandoraPlayer {
-    pub fn new(config: Config) -> PandoraPlayer {
-        let handle = Pandora::new(&config.pandora_username, &config.pandora_password).expect("Couldn't initialize pandora.");
-        let stations = handle.stations().list();
+    /// Logs in and lists the account's stations.
+    pub fn new(config: Config) -> Result<PandoraPlayer, failure::Error> {
+        let handle = Pandora::new(&config.pandora_username, &config.pandora_password)
+            .map_err(|e| failure::err_msg(format!("Couldn't log in to pandora: {:?}", e)))?;
+        let stations = handle.stations().list().map_err(|e| failure::err_msg(format!("Couldn't list pandora stations: {:?}", e)))?;
+        Ok(PandoraPlayer::with_session(config, Some(handle), stations))
+    }
+
+    /// Without a handle no new playlists are fetched and ratings are dropped, only tracks already
+    /// in `current_playlist` can play.
+    pub fn with_session(config: Config, handle: Option<Pandora>, stations: Vec<Station>) -> PandoraPlayer {
         let mut stations_names: Vec<String> = Vec::new();
-        for s in stations.as_ref().unwrap().iter() { stations_names.push(s.station_name.clone()); }
+        for s in stations.iter() { stations_names.push(s.station_name.clone()); }
         PandoraPlayer {
             handle: handle,
             config: config,
-            stations: stations.unwrap(),
+            stations: stations,
             selected_idx: None,
             selected_station: None,
             stations_names: stations_names,
@@ -42,10 +55,60 @@ impl PandoraPlayer {
             rebuild_station_list: false,
             current_playlist: Some(Vec::new()),
             current_playlist_titles: Some(Vec::new()),
+            preloaded_idx: None,
+            search: Search::new(),
+        }
+    }
+
+    /// The station being played, or the one under the cursor in the station list.
+    pub fn station_name(&self) -> Option<String> {
+        self.selected_station.or(if self.viewing_stations { self.selected_idx } else { None })
+            .and_then(|idx| self.stations.get(idx))
+            .map(|s| s.station_name.clone())
+    }
+
+    /// Moves the station list cursor to `name`, so Space picks up where the last session was.
+    /// Tracks aren't restored since their urls expire.
+    pub fn restore_station(&mut self, name: &str) {
+        if let Some(idx) = self.stations.iter().position(|s| s.station_name == name) {
+            self.selected_idx = Some(idx);
+            self.rebuild_station_list = true;
+        }
+    }
+
+    pub fn capturing_input(&self) -> bool {
+        self.viewing_stations && self.search.editing()
+    }
+
+    pub fn next(&mut self, media_player: &mut MediaPlayer) {
+        if !self.viewing_stations {
+            self.next_track(media_player);
+        }
+    }
+
+    pub fn previous(&mut self, media_player: &mut MediaPlayer) {
+        if !self.viewing_stations {
+            // only step back to tracks from this station, so the playlist position can follow
+            let position = media_player.history.peek_playable().and_then(|song| {
+                self.current_playlist.as_ref().unwrap().iter().position(|t| t.additional_audio_url.as_ref() == Some(&song.path))
+            });
+            if let Some(idx) = position {
+                media_player.previous();
+                self.selected_idx = Some(idx);
+            }
         }
     }
 
-    fn next_track(&mut self, fmod: &Sys, media_player: &mut MediaPlayer) {
+    /// Stops playback and goes back to the station list.
+    pub fn stop(&mut self, media_player: &mut MediaPlayer) {
+        self.current_playlist = Some(Vec::new());
+        self.current_playlist_titles = Some(Vec::new());
+        self.selected_idx = self.selected_station;
+        media_player.stop();
+        self.viewing_stations = true;
+    }
+
+    fn next_track(&mut self, media_player: &mut MediaPlayer) {
         if let Some(mut idx) = self.selected_idx {
             let cur_len = self.current_playlist.as_ref().unwrap().len();
             
@@ -58,14 +121,66 @@ impl PandoraPlayer {
                 self.next_playlist();
             }
 
-            let url = &self.current_playlist.as_ref().expect("Couldn't unwrap current playlist")[idx].additional_audio_url.clone().unwrap();
-            media_player.play_from_uri(fmod, &url);
+            let playlist = self.current_playlist.as_ref().expect("Couldn't unwrap current playlist");
+            match playlist.get(idx) {
+                Some(track) => media_player.play_song(PandoraPlayer::track_to_song(track)),
+                // no more tracks could be fetched, so stay on the last one rather than point past the end
+                None => self.selected_idx = playlist.len().checked_sub(1)
+            }
+        }
+    }
+
+    // pandora tracks stream from additional_audio_url, which stands in for a local path
+    fn track_to_song(track: &Track) -> Song {
+        Song {
+            name: track.song_name.clone().unwrap_or_default(),
+            path: track.additional_audio_url.clone().unwrap_or_default(),
+            artist: track.artist_name.clone().unwrap_or_default(),
+            album: track.album_name.clone().unwrap_or_default(),
+            genre: String::new(),
+            year: 0,
+            track: 0,
+            length: 0,
+            // pandora normalizes its own catalogue and sends the adjustment in dB
+            replay_gain: ReplayGain { track_gain: track.track_gain.as_ref().and_then(|g| replaygain::parse_gain(g)), ..ReplayGain::default() },
+            art_url: track.album_art_url.clone().unwrap_or_default(),
+        }
+    }
+
+    // loads the following track early so media_player can crossfade or run straight into it.
+    // only tracks already fetched are preloaded, next_track deals with getting a new playlist
+    fn preload_next(&mut self, media_player: &mut MediaPlayer) {
+        match media_player.play_mode {
+            PlayMode::RepeatOne | PlayMode::StopAtEnd => return,
+            _ => {}
+        }
+        if let (Some(selected), true) = (self.selected_idx, media_player.wants_next()) {
+            if let Some(track) = self.current_playlist.as_ref().and_then(|playlist| playlist.get(selected + 1)) {
+                if media_player.prepare_next(PandoraPlayer::track_to_song(track)) {
+                    self.preloaded_idx = Some(selected + 1);
+                }
+            }
+        }
+    }
+
+    fn follow_preloaded(&mut self, media_player: &MediaPlayer) {
+        if let Some(preloaded) = self.preloaded_idx {
+            if !media_player.has_prepared() {
+                self.preloaded_idx = None;
+                let url = self.current_playlist.as_ref().and_then(|playlist| playlist.get(preloaded)).and_then(|t| t.additional_audio_url.as_ref());
+                if url.is_some() && media_player.now_playing.as_ref().map(|s| &s.path) == url {
+                    self.selected_idx = Some(preloaded);
+                }
+            }
         }
     }
 
     fn next_playlist(&mut self) {
         let mut playlist = self.current_playlist.clone().unwrap();
-        let station_handle = self.handle.stations();
+        let station_handle = match self.handle.as_ref() {
+            Some(handle) => handle.stations(),
+            None => return
+        };
         if let Some(idx) = self.selected_station {
             let current_playlist_handle = Some(station_handle.playlist(&self.stations[idx]));
             if let Ok(new_playlist) = current_playlist_handle.as_ref().unwrap().list() {
@@ -91,76 +206,88 @@ impl Player for PandoraPlayer {
                 .direction(Direction::Horizontal)
                 .constraints(vec![Constraint::Percentage(100)])
                 .split(chunk);
+            let (rows, row) = self.search.filter_view(&self.stations_names, self.selected_idx);
             SelectableList::default()
-                .block(Block::default().borders(Borders::ALL).title(&format!("Station List")))
-                .items(&self.stations_names)
-                .select(self.selected_idx)
+                .block(Block::default().borders(Borders::ALL).title(&self.search.title("Station List")))
+                .items(&rows)
+                .select(row)
                 .style(Style::default().fg(Color::White))
                 .render(f, chunks[0]);
         } else if let Some(playlist_titles) = self.current_playlist_titles.as_ref() {
             if let Some(idx) = self.selected_idx {
                 media_player.draw(f, chunk, &self.stations_names[self.selected_station.unwrap()][2..], 
                                              playlist_titles.to_vec(), 
-                                             idx, 
-                                             self.current_playlist.as_ref().unwrap()[idx].artist_name.clone().unwrap(),
-                                             self.current_playlist.as_ref().unwrap()[idx].album_name.clone().unwrap());
+                                             idx);
             }
         }
     }
 
-    fn input(&mut self, key: Key, fmod: &Sys, media_player: &mut MediaPlayer) {
+    fn input(&mut self, key: Key, media_player: &mut MediaPlayer) {
         let selection_list_length = match self.viewing_stations {
             true => self.stations_names.len(),
             false => self.current_playlist_titles.as_ref().unwrap().len()
         };
 
+        if self.viewing_stations {
+            let haystacks: Vec<String> = self.stations.iter().map(|s| s.station_name.clone()).collect();
+            if self.search.input(key, &haystacks) {
+                self.selected_idx = self.search.constrain(self.selected_idx);
+                self.rebuild_station_list = true;
+                return;
+            }
+            if self.search.active() {
+                let forward = match key {
+                    Key::Down | Key::Char('n') => Some(true),
+                    Key::Up | Key::Char('N') => Some(false),
+                    _ => None
+                };
+                if let Some(forward) = forward {
+                    self.selected_idx = self.search.step(self.selected_idx, forward);
+                    self.rebuild_station_list = true;
+                    return;
+                }
+            }
+        }
+
         match key {
             Key::Char(' ') => {
                 if self.viewing_stations {
+                    self.search.clear();
                     self.selected_station = self.selected_idx;
                     self.selected_idx = Some(0);
-                    self.next_track(fmod, media_player);
+                    self.next_track(media_player);
                 } else {
                     media_player.toggle_pause();
                 }
             }
-            Key::Char('n') => {
-                if !self.viewing_stations {
-                    self.next_track(fmod, media_player);
-                }
+            Key::Char('n') => self.next(media_player),
+            Key::Char('m') => {
+                media_player.play_mode = media_player.play_mode.next();
             }
-            Key::Char('x') => {
+            Key::Char('p') => self.previous(media_player),
+            Key::Char('e') => {
                 if !self.viewing_stations {
-                    media_player.back();
+                    if let (Some(playlist), Some(idx)) = (self.current_playlist.as_ref(), self.selected_idx) {
+                        media_player.queue.push(PandoraPlayer::track_to_song(&playlist[idx]));
+                    }
                 }
             }
-            Key::Char('z') => {
-                if !self.viewing_stations {
-                    media_player.forward();
-                }                    
-            }
             Key::Ctrl('b') => {
                 if !self.viewing_stations {
-                    if let Some(playlist) = self.current_playlist.as_ref() {
+                    if let (Some(playlist), Some(handle)) = (self.current_playlist.as_ref(), self.handle.as_ref()) {
                         let track = &playlist[self.selected_idx.unwrap()];
-                        self.handle.request_noop(Method::StationAddFeedback, Some(serde_json::to_value(RateTrackRequest {
+                        handle.request_noop(Method::StationAddFeedback, Some(serde_json::to_value(RateTrackRequest {
                                                                                         station_token: self.stations[self.selected_station.unwrap()].to_station_token(),
                                                                                         track_token: track.to_track_token().unwrap_or("".to_owned()),
                                                                                         is_positive: false,
                                                                                     }).unwrap()));
-                        self.next_track(fmod, media_player);
+                        self.next_track(media_player);
                     }
                 }
             }
-            Key::Char('s') => {
-                    self.current_playlist = Some(Vec::new());
-                    self.current_playlist_titles = Some(Vec::new());
-                    self.selected_idx = self.selected_station;
-                    media_player.pause();
-                    self.viewing_stations = true;
-            }
+            Key::Char('s') => self.stop(media_player),
             Key::Down => {
-                if self.viewing_stations { 
+                if self.viewing_stations && selection_list_length > 0 {
                     self.selected_idx = if let Some(selected) = self.selected_idx {
                         if selected >= selection_list_length - 1 {
                             Some(0)
@@ -174,7 +301,7 @@ impl Player for PandoraPlayer {
                 }
             }
             Key::Up => {
-                if self.viewing_stations { 
+                if self.viewing_stations && selection_list_length > 0 {
                     self.selected_idx = if let Some(selected) = self.selected_idx {
                         if selected > 0 {
                             Some(selected - 1)
@@ -191,7 +318,7 @@ impl Player for PandoraPlayer {
         }
     }
 
-    fn tick(&mut self, fmod: &Sys, media_player: &mut MediaPlayer) {
+    fn tick(&mut self, media_player: &mut MediaPlayer) {
         // draw > in list
         if self.rebuild_station_list && self.viewing_stations {
             self.stations_names.clear();
@@ -205,13 +332,113 @@ impl Player for PandoraPlayer {
         if !self.viewing_stations {
             if let Some(selected) = self.selected_idx {
                 if media_player.almost_over() { 
-                    self.next_track(fmod, media_player);
-                }
-                
-                if *media_player.last_song_title.as_ref().unwrap() != self.current_playlist_titles.as_ref().unwrap()[selected] {
-                    self.next_track(fmod, media_player); // nonworking attempt at context switch
+                    match media_player.play_mode {
+                        PlayMode::RepeatOne => {
+                            let track = &self.current_playlist.as_ref().unwrap()[selected];
+                            media_player.play_song(PandoraPlayer::track_to_song(track));
+                        }
+                        PlayMode::StopAtEnd => media_player.stop(),
+                        _ => self.next_track(media_player)
+                    }
                 }
             }
+            self.preload_next(media_player);
+            self.follow_preloaded(media_player);
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::audio::ManualClock;
+    use super::super::audio::testing::media_player;
+
+    fn track(name: &str) -> Track {
+        serde_json::from_str(&format!(r#"{{ "songName": "{}", "artistName": "artist", "additionalAudioUrl": "http://example.com/{}.mp3" }}"#, name, name)).unwrap()
+    }
+
+    fn setup(names: &[&str]) -> (PandoraPlayer, MediaPlayer, ManualClock) {
+        let (media_player, clock) = media_player(Config::default(), &[]);
+        let mut pandora_player = PandoraPlayer::with_session(Config::default(), None, Vec::new());
+        pandora_player.current_playlist = Some(names.iter().map(|n| track(n)).collect());
+        pandora_player.current_playlist_titles = Some(names.iter().map(|n| String::from(*n)).collect());
+        pandora_player.viewing_stations = false;
+        pandora_player.selected_idx = Some(0);
+        (pandora_player, media_player, clock)
+    }
+
+    #[test]
+    fn next_track_steps_through_playlist() {
+        let (mut pandora_player, mut media_player, _clock) = setup(&["one", "two", "three"]);
+        pandora_player.next_track(&mut media_player);
+        assert_eq!(pandora_player.selected_idx, Some(1));
+        let song = media_player.now_playing.clone().unwrap();
+        assert_eq!(song.name, "two");
+        assert_eq!(song.path, "http://example.com/two.mp3");
+
+        pandora_player.next_track(&mut media_player);
+        assert_eq!(media_player.now_playing.as_ref().unwrap().name, "three");
+        assert_eq!(media_player.history.len(), 1);
+    }
+
+    #[test]
+    fn next_track_without_session_stops_at_end_of_playlist() {
+        let (mut pandora_player, mut media_player, _clock) = setup(&["one"]);
+        pandora_player.next_track(&mut media_player);
+        assert_eq!(pandora_player.selected_idx, Some(0));
+        assert!(media_player.now_playing.is_none());
+
+        // nothing is playing, so tick tries to move on again and must stay in range
+        pandora_player.tick(&mut media_player);
+        assert_eq!(pandora_player.selected_idx, Some(0));
+        media_player.play_mode = PlayMode::RepeatOne;
+        pandora_player.tick(&mut media_player);
+        assert_eq!(media_player.now_playing.as_ref().unwrap().name, "one");
+    }
+
+    #[test]
+    fn tick_advances_when_track_ends() {
+        let (mut pandora_player, mut media_player, clock) = setup(&["one", "two"]);
+        media_player.play_song(PandoraPlayer::track_to_song(&track("one")));
+        pandora_player.tick(&mut media_player);
+        assert_eq!(media_player.now_playing.as_ref().unwrap().name, "one");
+
+        // streams have no known length in the null backend, so they run for its default
+        clock.advance(3 * 60 * 1000);
+        pandora_player.tick(&mut media_player);
+        assert_eq!(pandora_player.selected_idx, Some(1));
+        assert_eq!(media_player.now_playing.as_ref().unwrap().name, "two");
+    }
+
+    #[test]
+    fn queued_song_keeps_playing_over_the_station() {
+        let (mut pandora_player, mut media_player, clock) = setup(&["one", "two"]);
+        media_player.play_song(PandoraPlayer::track_to_song(&track("one")));
+        media_player.queue.push(PandoraPlayer::track_to_song(&track("queued")));
+        clock.advance(3 * 60 * 1000);
+        media_player.tick();
+        pandora_player.tick(&mut media_player);
+        assert_eq!(media_player.now_playing.as_ref().unwrap().name, "queued");
+
+        // drawing shows the queued song's title, which used to look like a station change
+        media_player.last_song_title = Some(String::from("queued"));
+        clock.advance(1000);
+        media_player.tick();
+        pandora_player.tick(&mut media_player);
+        assert_eq!(media_player.now_playing.as_ref().unwrap().name, "queued");
+        assert_eq!(pandora_player.selected_idx, Some(0));
+    }
+
+    #[test]
+    fn station_list_without_a_login_is_empty_but_usable() {
+        let mut pandora_player = PandoraPlayer::with_session(Config::default(), None, Vec::new());
+        let (mut media_player, _clock) = media_player(Config::default(), &[]);
+        for key in [Key::Down, Key::Down, Key::Up, Key::Char(' ')].iter() {
+            pandora_player.input(*key, &mut media_player);
+            pandora_player.tick(&mut media_player);
+        }
+        assert!(media_player.now_playing.is_none());
+        assert_eq!(pandora_player.station_name(), None);
+    }
+}
@@ -1,19 +1,61 @@
 use std::cmp;
+use std::f32::consts::FRAC_PI_2;
 use termion::event::Key;
 use tui::terminal::Frame;
 use tui::backend::Backend;
 use tui::widgets::{ Widget, Block, Borders, SelectableList, Gauge, BarChart, Paragraph, Text };
 use tui::style::{ Color, Modifier, Style};
 use tui::layout::{ Rect, Layout, Constraint, Direction, Alignment };
-use rfmod::Sys;
 use super::{ Config, LyricsGrabber };
+use super::audio::{ self, AudioBackend, SoundId, ChannelId };
+use super::queue::Queue;
+use super::order::PlayMode;
+use super::history::History;
+use super::state::{ PersistentState, Session };
+use super::replaygain::ReplayGain;
 
+// how close to the end of a track the next one gets loaded and scheduled
+const GAPLESS_PRELOAD_MS: u32 = 5000;
+const VOLUME_STEP: f32 = 0.05;
+// used when the config leaves seek_step_ms / seek_step_large_ms out
+const DEFAULT_SEEK_STEP_MS: u32 = 10000;
+const DEFAULT_SEEK_STEP_LARGE_MS: u32 = 60000;
+// files at least this long get their position remembered, unless resume_threshold_secs says otherwise
+const DEFAULT_RESUME_THRESHOLD_SECS: u32 = 20 * 60;
+// how often the position of a long file is written out while it plays
+const RESUME_SAVE_INTERVAL_MS: u32 = 30000;
+// stopping this close to the end counts as finished, so there's nothing to resume
+const RESUME_END_MARGIN_MS: u32 = 30000;
+// t steps through these before the end of track/album options
+const SLEEP_PRESET_MINUTES: [u64; 5] = [15, 30, 45, 60, 90];
+const SLEEP_FADE_MS: u64 = 30000;
+const RATE_STEP: f32 = 0.1;
+pub const MIN_RATE: f32 = 0.5;
+pub const MAX_RATE: f32 = 3.0;
+// B is kept this far from the end so the track can't run out before the loop jumps back
+const LOOP_END_MARGIN_MS: u32 = 250;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Song {
     pub name: String,
     pub path: String,
     pub artist: String,
     pub album: String,
+    pub genre: String,
+    pub year: u32,
+    pub track: u32,
     pub length: u32,
+    #[serde(default)]
+    pub replay_gain: ReplayGain,
+    // cover art url, only pandora tracks come with one
+    #[serde(default)]
+    pub art_url: String,
+}
+
+impl Song {
+    pub fn is_stream(&self) -> bool {
+        self.path.starts_with("http://") || self.path.starts_with("https://")
+    }
 }
 
 pub struct Playlist {
@@ -23,24 +65,115 @@ pub struct Playlist {
 }
 
 pub trait Player {
-    fn input(&mut self, key: Key, fmod: &Sys, media_player: &mut MediaPlayer);
-    fn tick(&mut self, fmod: &Sys, media_player: &mut MediaPlayer);
+    fn input(&mut self, key: Key, media_player: &mut MediaPlayer);
+    fn tick(&mut self, media_player: &mut MediaPlayer);
     fn draw<B: Backend>(&mut self, f: &mut Frame<B>, chunk: Rect, media_player: &mut MediaPlayer);
 }
 
+// a song loaded ahead of time to start the moment the current one ends, or fade_ms before it
+struct PreparedSong {
+    song: Song,
+    sound: SoundId,
+    channel: Option<ChannelId>,
+    fade_ms: u32,
+}
+
+// the previous song while it fades out under the new one
+struct FadeOut {
+    sound: SoundId,
+    channel: ChannelId,
+    fade_ms: u32,
+    replay_gain: f32,
+}
+
+/// Formats milliseconds as "m:ss", or "h:mm:ss" past an hour.
+pub fn format_timestamp(ms: u32) -> String {
+    let seconds = ms / 1000;
+    match seconds / 3600 {
+        0 => format!("{}:{:02}", seconds / 60, seconds % 60),
+        hours => format!("{}:{:02}:{:02}", hours, seconds / 60 % 60, seconds % 60)
+    }
+}
+
+/// Parses "ss", "mm:ss" or "hh:mm:ss" into milliseconds.
+pub fn parse_timestamp(text: &str) -> Option<u32> {
+    if text.is_empty() { return None; }
+    let mut seconds: u32 = 0;
+    for (idx, part) in text.split(':').enumerate() {
+        if idx > 2 || part.is_empty() { return None; }
+        seconds = seconds.checked_mul(60)?.checked_add(part.parse().ok()?)?;
+    }
+    seconds.checked_mul(1000)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SleepTimer {
+    // index into SLEEP_PRESET_MINUTES and the backend clock time to pause at
+    After(usize, u64),
+    EndOfTrack,
+    EndOfAlbum,
+}
+
+// equal power curve, so the overlap doesn't dip in loudness halfway through
+fn fade_gain(progress: f32) -> f32 {
+    (progress.max(0.0).min(1.0) * FRAC_PI_2).sin()
+}
+
+// the loop drawn along the gauge's bottom border, returned as (column, text)
+fn loop_marker(width: u16, length: u32, a: u32, b: Option<u32>) -> (u16, String) {
+    let column = |ms: u32| cmp::min((ms as u64 * width as u64 / cmp::max(length, 1) as u64) as u16, width.saturating_sub(1));
+    match b {
+        Some(b) => {
+            let start = cmp::min(column(a), width.saturating_sub(2));
+            let end = cmp::max(column(b), start + 1);
+            (start, format!("A{}B", "─".repeat((end - start - 1) as usize)))
+        }
+        None => (column(a), String::from("A"))
+    }
+}
+
 pub struct MediaPlayer {
     pub num_spectrum_bars: usize,
     pub spectrum_data_last: Vec<f32>,
     pub last_song_title: Option<String>,
-    pub playing_song_handle: Option<rfmod::Sound>,
-    pub playing_channel: Option<rfmod::Channel>,
+    pub playing_song_handle: Option<SoundId>,
+    pub playing_channel: Option<ChannelId>,
     pub playing_song_title: Option<String>,
     pub playing_song_lyrics: Option<String>,
+    pub now_playing: Option<Song>,
+    pub queue: Queue,
+    pub play_mode: PlayMode,
+    pub history: History,
+    pub error: Option<String>,
+    // text typed into the go to time prompt while it's open
+    goto: Option<String>,
+    // stored position offered for the song that just started, until y or n is pressed
+    resume_offer: Option<u32>,
+    // position last written to the state file for the current song
+    resume_saved_at: Option<u32>,
+    sleep: Option<SleepTimer>,
+    // A-B repeat loop, B is None while only A has been marked
+    ab_loop: Option<(u32, Option<u32>)>,
+    // the sleep timer stopped playback at the end of a track, the views mustn't move on until play resumes
+    held: bool,
+    next: Option<PreparedSong>,
+    fading_out: Option<FadeOut>,
+    fade_in_ms: u32,
+    state: PersistentState,
+    backend: Box<dyn AudioBackend>,
     config: Config
 }
 
 impl MediaPlayer {
-    pub fn new(config: Config) -> MediaPlayer {
+    pub fn new(config: Config) -> Result<MediaPlayer, failure::Error> {
+          let backend = audio::create_backend(&config.audio_backend)
+              .map_err(|e| failure::err_msg(format!("Couldn't start audio backend: {}", e)))?;
+          let mut media_player = MediaPlayer::with_backend(config, backend);
+          media_player.state = PersistentState::load();
+          Ok(media_player)
+    }
+
+    pub fn with_backend(config: Config, backend: Box<dyn AudioBackend>) -> MediaPlayer {
           MediaPlayer {
               num_spectrum_bars: 70,
               spectrum_data_last: vec![0f32; 70],
@@ -49,58 +182,420 @@ impl MediaPlayer {
               playing_channel: None,
               playing_song_title: None,
               playing_song_lyrics: None,
+              now_playing: None,
+              queue: Queue::new(),
+              play_mode: PlayMode::RepeatAll,
+              history: History::new(),
+              error: None,
+              goto: None,
+              resume_offer: None,
+              resume_saved_at: None,
+              sleep: None,
+              ab_loop: None,
+              held: false,
+              next: None,
+              fading_out: None,
+              fade_in_ms: 0,
+              state: PersistentState::new(),
+              backend: backend,
               config: config
           }
     }
 
-    pub fn forward(&self) {
-        self.playing_channel.as_ref().unwrap().set_position(cmp::max(0, self.playing_channel.as_ref().unwrap().get_position(rfmod::TIMEUNIT_MS).unwrap() as i32 - 10000) as usize, rfmod::TIMEUNIT_MS);
+    pub fn position(&self) -> Option<u32> {
+        self.playing_channel.and_then(|c| self.backend.position(c))
+    }
+
+    pub fn length(&self) -> Option<u32> {
+        self.playing_song_handle.and_then(|s| self.backend.length(s))
+    }
+
+    pub fn seek_step_ms(&self) -> u32 {
+        match self.config.seek_step_ms { 0 => DEFAULT_SEEK_STEP_MS, ms => ms }
+    }
+
+    pub fn seek_step_large_ms(&self) -> u32 {
+        match self.config.seek_step_large_ms { 0 => DEFAULT_SEEK_STEP_LARGE_MS, ms => ms }
+    }
+
+    pub fn seek_forward(&mut self, ms: u32) {
+        if let Some(position) = self.position() { self.set_position(position.saturating_add(ms)); }
+    }
+
+    pub fn seek_backward(&mut self, ms: u32) {
+        if let Some(position) = self.position() { self.set_position(position.saturating_sub(ms)); }
     }
 
-    pub fn back(&self) {
-        self.playing_channel.as_ref().unwrap().set_position(self.playing_channel.as_ref().unwrap().get_position(rfmod::TIMEUNIT_MS).unwrap() + 10000, rfmod::TIMEUNIT_MS);
+    /// Jumps to `percent` of the way through the track.
+    pub fn seek_to_percent(&mut self, percent: u32) {
+        if let Some(length) = self.length() {
+            self.set_position((length as u64 * cmp::min(percent, 100) as u64 / 100) as u32);
+        }
     }
 
+    /// True when the caller should start the next song itself. Never true while a prepared
+    /// song is waiting to take over, since `tick` switches to it without a gap.
     pub fn almost_over(&self) -> bool {
-        if let (Some(channel), Some(song_handle)) = (self.playing_channel.as_ref(), self.playing_song_handle.as_ref()) {
-            if let (Ok(position_ms), Ok(song_length_ms)) = (channel.get_position(rfmod::TIMEUNIT_MS), song_handle.get_length(rfmod::TIMEUNIT_MS)) {
-                return (position_ms as u32) >= (song_length_ms as u32) - 5
+        if self.held { return false; }
+        if self.next.as_ref().map_or(false, |next| next.channel.is_some()) { return false; }
+        if let Some(channel) = self.playing_channel {
+            if !self.backend.is_playing(channel) { return true; }
+            if let (Some(position_ms), Some(song_length_ms)) = (self.position(), self.length()) {
+                return position_ms >= song_length_ms.saturating_sub(5)
             }
         }
         true
     }
 
-    pub fn pause(&self) {
-        self.playing_channel.as_ref().unwrap().set_paused(true);
+    pub fn paused(&self) -> bool {
+        self.playing_channel.map_or(false, |c| self.backend.is_paused(c))
     }
 
-    pub fn toggle_pause(&self) {
-        self.playing_channel.as_ref().unwrap().set_paused(!self.playing_channel.as_ref().unwrap().get_paused().unwrap());
+    pub fn pause(&mut self) {
+        self.interrupt_transition();
+        if let Some(channel) = self.playing_channel { self.backend.set_paused(channel, true); }
     }
 
-    pub fn set_position(&self, loc: usize) {
-        self.playing_channel.as_ref().unwrap().set_position(loc, rfmod::TIMEUNIT_MS);
+    pub fn stop(&mut self) {
+        self.remember_position();
+        self.held = false;
+        self.ab_loop = None;
+        self.drop_next();
+        self.finish_fade();
+        if self.playing_channel.is_some() { self.pause(); }
+        self.now_playing = None;
     }
 
-    pub fn play_from_uri(&mut self, fmod: &Sys, path: &str) {
-        let playing_song_handle = match fmod.create_sound(path, None, None) {
+    pub fn toggle_pause(&mut self) {
+        self.interrupt_transition();
+        self.held = false;
+        let paused = self.paused();
+        if let Some(channel) = self.playing_channel { self.backend.set_paused(channel, !paused); }
+    }
+
+    /// Seeks the current track, clamped to its length.
+    pub fn set_position(&mut self, loc: u32) {
+        self.interrupt_transition();
+        let loc = match self.length() { Some(length) => cmp::min(loc, length), None => loc };
+        if let Some(channel) = self.playing_channel { self.backend.seek(channel, loc); }
+    }
+
+    pub fn play_song(&mut self, song: Song) {
+        self.remember_position();
+        if let Some(previous) = self.now_playing.take() {
+            self.history.push(previous);
+        }
+        if self.play_from_uri(&song.path) {
+            self.now_playing = Some(song);
+            self.update_levels();
+            self.offer_resume();
+        }
+    }
+
+    /// Puts `song` back where a previous run left it, paused so starting up stays quiet.
+    pub fn restore(&mut self, song: Song, position: u32) {
+        self.play_song(song);
+        self.resume_offer = None;
+        self.set_position(position);
+        self.pause();
+    }
+
+    pub fn session(&self) -> Option<&Session> {
+        self.state.session.as_ref()
+    }
+
+    pub fn save_session(&mut self, session: Session) {
+        self.state.session = Some(session);
+        self.save_state();
+    }
+
+    /// Replays the most recent playable history entry, returning it. The current song isn't
+    /// added to history, so pressing previous repeatedly keeps walking backwards.
+    pub fn previous(&mut self) -> Option<Song> {
+        let song = self.history.pop_playable()?;
+        self.remember_position();
+        if !self.play_from_uri(&song.path) { return None; }
+        self.now_playing = Some(song.clone());
+        self.update_levels();
+        self.offer_resume();
+        Some(song)
+    }
+
+    /// Swaps the playing sound for `path`. On failure the old sound is stopped anyway and the
+    /// reason is kept in `error` for the views to show.
+    pub fn play_from_uri(&mut self, path: &str) -> bool {
+        self.held = false;
+        self.ab_loop = None;
+        self.drop_next();
+        self.finish_fade();
+        if let Some(channel) = self.playing_channel.take() { self.backend.stop(channel); }
+        if let Some(sound) = self.playing_song_handle.take() { self.backend.release(sound); }
+        self.playing_song_lyrics = None;
+
+        let sound = match self.backend.load(path) {
             Ok(s) => s,
-            Err(err) => panic!("Error code: {:?}", err)
+            Err(e) => { self.error = Some(e.to_string()); return false; }
         };
-        let playing_channel = match playing_song_handle.play() {
-            Ok(c) => c,
-            Err(err) => panic!("Play: {:?}", err)
+        match self.backend.play(sound) {
+            Ok(channel) => {
+                self.backend.set_volume(channel, self.gain());
+                self.backend.set_rate(channel, self.state.rate(path));
+                self.playing_song_handle = Some(sound);
+                self.playing_channel = Some(channel);
+                self.error = None;
+                true
+            }
+            Err(e) => {
+                self.backend.release(sound);
+                self.error = Some(e.to_string());
+                false
+            }
+        }
+    }
+
+    /// True once the current song is close enough to its end that the next one should be
+    /// handed to `prepare_next`.
+    pub fn wants_next(&self) -> bool {
+        if self.now_playing.is_none() || self.next.is_some() || self.paused() || self.held { return false; }
+        if self.sleep == Some(SleepTimer::EndOfTrack) || self.looping() { return false; }
+        match (self.position(), self.length()) {
+            (Some(position), Some(length)) => length.saturating_sub(position) <= GAPLESS_PRELOAD_MS + self.config.crossfade_ms,
+            _ => false
+        }
+    }
+
+    /// Loads `song` and schedules it to start exactly when the current song ends, or to
+    /// crossfade into it if that's configured.
+    pub fn prepare_next(&mut self, song: Song) -> bool {
+        self.drop_next();
+        let fade_ms = self.crossfade_ms_to(&song);
+        match self.backend.load(&song.path) {
+            Ok(sound) => {
+                self.next = Some(PreparedSong { song: song, sound: sound, channel: None, fade_ms: fade_ms });
+                self.schedule_next();
+                self.promote_if_finished();
+                true
+            }
+            Err(e) => {
+                self.error = Some(e.to_string());
+                false
+            }
+        }
+    }
+
+    pub fn has_prepared(&self) -> bool {
+        self.next.is_some()
+    }
+
+    // the prepared sound stays loaded, tick schedules it again once playback is steady
+    fn unschedule_next(&mut self) {
+        if let Some(channel) = self.next.as_mut().and_then(|next| next.channel.take()) {
+            self.backend.stop(channel);
+        }
+    }
+
+    fn drop_next(&mut self) {
+        self.unschedule_next();
+        if let Some(next) = self.next.take() {
+            self.backend.release(next.sound);
+        }
+    }
+
+    // cuts a crossfade short, the new song jumps to full volume
+    fn finish_fade(&mut self) {
+        if let Some(fade) = self.fading_out.take() {
+            self.backend.stop(fade.channel);
+            self.backend.release(fade.sound);
+        }
+        if self.fade_in_ms > 0 {
+            self.fade_in_ms = 0;
+            let level = self.level(self.now_playing.as_ref());
+            if let Some(channel) = self.playing_channel { self.backend.set_volume(channel, level); }
+        }
+    }
+
+    // the playhead moved or stopped, so anything timed against it has to be redone
+    fn interrupt_transition(&mut self) {
+        self.unschedule_next();
+        self.finish_fade();
+    }
+
+    /// How long `song` should overlap the current one. Tracks from the same album run into each other
+    /// as written, so they never crossfade.
+    fn crossfade_ms_to(&self, song: &Song) -> u32 {
+        let current = match &self.now_playing {
+            Some(current) => current,
+            None => return 0
         };
-        
-        self.playing_song_handle = Some(playing_song_handle);
-        self.playing_channel = Some(playing_channel);
+        if !song.album.is_empty() && song.album == current.album {
+            return 0;
+        }
+        // never fade longer than half of either track, streams don't know their length up front
+        let mut longest = self.length().unwrap_or(0) / 2;
+        if song.length > 0 { longest = cmp::min(longest, song.length / 2); }
+        cmp::min(self.config.crossfade_ms, longest)
+    }
+
+    // linear factor from the song's replaygain tags, following the configured mode
+    fn replay_gain(&self, song: Option<&Song>) -> f32 {
+        match (song, self.config.replay_gain.use_album(self.play_mode)) {
+            (Some(song), Some(album)) => song.replay_gain.scale(album, self.config.replay_gain_preamp_db),
+            _ => 1.0
+        }
+    }
+
+    // what a channel playing `song` should be set to outside of fades
+    fn level(&self, song: Option<&Song>) -> f32 {
+        self.gain() * self.replay_gain(song)
+    }
+
+    // runs every tick, which also picks up replaygain mode changes that follow the play mode
+    fn update_levels(&mut self) {
+        if let Some(fade) = self.fading_out.as_ref() {
+            if self.backend.is_playing(fade.channel) {
+                let remaining = self.backend.length(fade.sound).unwrap_or(0).saturating_sub(self.backend.position(fade.channel).unwrap_or(0));
+                self.backend.set_volume(fade.channel, fade_gain(remaining as f32 / fade.fade_ms as f32) * self.gain() * fade.replay_gain);
+            } else {
+                let fade = self.fading_out.take().unwrap();
+                self.backend.stop(fade.channel);
+                self.backend.release(fade.sound);
+            }
+        }
+        let level = self.level(self.now_playing.as_ref());
+        if let (Some(channel), Some(position)) = (self.playing_channel, self.position()) {
+            if position >= self.fade_in_ms {
+                self.backend.set_volume(channel, level);
+                self.fade_in_ms = 0;
+            } else {
+                self.backend.set_volume(channel, fade_gain(position as f32 / self.fade_in_ms as f32) * level);
+            }
+        }
+    }
+
+    fn schedule_next(&mut self) {
+        let current = match self.playing_channel {
+            Some(channel) if !self.paused() => channel,
+            _ => return
+        };
+        let (sound, fade_ms, rate) = match &self.next {
+            Some(next) if next.channel.is_none() && !self.sleeps_before(&next.song) => (next.sound, next.fade_ms, self.state.rate(&next.song.path)),
+            _ => return
+        };
+        match self.backend.play_after(sound, current, fade_ms) {
+            Ok(channel) => {
+                let level = if fade_ms > 0 { 0.0 } else { self.level(self.next.as_ref().map(|next| &next.song)) };
+                self.backend.set_volume(channel, level);
+                self.backend.set_rate(channel, rate);
+                if let Some(next) = self.next.as_mut() { next.channel = Some(channel); }
+            }
+            Err(e) => {
+                self.error = Some(e.to_string());
+                self.drop_next();
+            }
+        }
+    }
+
+    // the scheduled channel is already playing by now, this only catches up the bookkeeping
+    // with a crossfade the next song takes over as soon as it starts, the old one keeps fading underneath
+    fn promote_if_finished(&mut self) {
+        let remaining = match self.playing_channel {
+            Some(channel) if self.backend.is_playing(channel) => self.length().unwrap_or(0).saturating_sub(self.position().unwrap_or(0)),
+            _ => 0
+        };
+        let next = match self.next.take() {
+            Some(next) if next.channel.is_some() && (remaining == 0 || (next.fade_ms > 0 && remaining <= next.fade_ms)) => next,
+            next => { self.next = next; return; }
+        };
+        self.remember_position();
+        self.finish_fade();
+        self.fade_in_ms = next.fade_ms;
+        let replay_gain = self.replay_gain(self.now_playing.as_ref());
+        match (self.playing_channel.take(), self.playing_song_handle.take()) {
+            (Some(channel), Some(sound)) if next.fade_ms > 0 && remaining > 0 => {
+                self.fading_out = Some(FadeOut { sound: sound, channel: channel, fade_ms: next.fade_ms, replay_gain: replay_gain });
+            }
+            (channel, sound) => {
+                if let Some(channel) = channel { self.backend.stop(channel); }
+                if let Some(sound) = sound { self.backend.release(sound); }
+            }
+        }
+        if let Some(previous) = self.now_playing.take() {
+            self.history.push(previous);
+        }
+        self.playing_song_handle = Some(next.sound);
+        self.playing_channel = next.channel;
         self.playing_song_lyrics = None;
+        self.ab_loop = None;
+        self.now_playing = Some(next.song);
+        self.offer_resume();
+    }
+
+    fn resume_threshold_ms(&self) -> u32 {
+        match self.config.resume_threshold_secs { 0 => DEFAULT_RESUME_THRESHOLD_SECS, secs => secs }.saturating_mul(1000)
+    }
+
+    /// Stores where the current song is, if it's long enough to be worth resuming. Songs that
+    /// were played to (nearly) the end are forgotten instead.
+    pub fn remember_position(&mut self) {
+        let path = match &self.now_playing {
+            Some(song) => song.path.clone(),
+            None => return
+        };
+        let (position, length) = match (self.position(), self.length()) {
+            (Some(position), Some(length)) if length >= self.resume_threshold_ms() => (position, length),
+            _ => return
+        };
+        self.resume_saved_at = Some(position);
+        let position = if position > 0 && length.saturating_sub(position) > RESUME_END_MARGIN_MS { Some(position) } else { None };
+        self.state.set_resume_position(&path, position);
+        self.save_state();
+    }
+
+    fn offer_resume(&mut self) {
+        self.resume_saved_at = None;
+        self.resume_offer = self.now_playing.as_ref().and_then(|song| self.state.resume_position(&song.path));
     }
 
-    pub fn draw<B: Backend>(&mut self, f: &mut Frame<B>, chunk: Rect, list_title: &str, list_member_titles: Vec<String>, selected_idx: usize, artist: String, album: String) {
-        self.playing_song_title = Some(list_member_titles[selected_idx].clone());
-        if let Some(playing_channel) = &mut self.playing_channel {
-            if let Some(playing_song_handle) = &mut self.playing_song_handle {
+    /// Marks A, then B, then clears the loop. A B at or before A marks A again instead.
+    pub fn mark_loop_point(&mut self) {
+        let position = match self.position() { Some(position) => position, None => return };
+        self.ab_loop = match self.ab_loop {
+            None => Some((position, None)),
+            Some((a, None)) => {
+                let b = self.length().map_or(position, |length| cmp::min(position, length.saturating_sub(LOOP_END_MARGIN_MS)));
+                if b > a { Some((a, Some(b))) } else { Some((position, None)) }
+            }
+            Some((_, Some(_))) => None
+        };
+    }
+
+    pub fn ab_loop(&self) -> Option<(u32, Option<u32>)> {
+        self.ab_loop
+    }
+
+    fn looping(&self) -> bool {
+        self.ab_loop.map_or(false, |(_, b)| b.is_some())
+    }
+
+    fn update_loop(&mut self) {
+        if let (Some((a, Some(b))), Some(position)) = (self.ab_loop, self.position()) {
+            if position >= b { self.set_position(a); }
+        }
+    }
+
+    /// The stored position waiting on a y/n answer, if any.
+    pub fn resume_offer(&self) -> Option<u32> {
+        self.resume_offer
+    }
+
+    pub fn draw<B: Backend>(&mut self, f: &mut Frame<B>, chunk: Rect, list_title: &str, list_member_titles: Vec<String>, selected_idx: usize) {
+        let (artist, album) = match &self.now_playing {
+            Some(song) => (song.artist.clone(), song.album.clone()),
+            None => (String::new(), String::new())
+        };
+        self.playing_song_title = self.now_playing.as_ref().map(|song| song.name.clone());
+        if let Some(playing_channel) = self.playing_channel {
+            if self.playing_song_handle.is_some() {
                 if self.playing_song_lyrics == None { // will try to grab every iteration if not found first time, replace this with something better future me, I have responsibilites rn
                     self.playing_song_lyrics = Some(String::new()); //LyricsGrabber::grab_lyrics(artist.clone(), self.playing_song_title.as_ref().unwrap().to_string(), &self.config.genius_token);
                 }
@@ -118,10 +613,11 @@ impl MediaPlayer {
                     .highlight_style(select_list_style.modifier(Modifier::BOLD))
                     .render(f, chunks[1]);
                 
-                let time_ms = playing_channel.get_position(rfmod::TIMEUNIT_MS).unwrap() as f32;
+                let time_ms = self.position().unwrap_or(0) as f32;
+                let length_ms = cmp::max(self.length().unwrap_or(0), 1) as f32;
                 let time_s = time_ms / 1000.0 % 60.0;
                 let time_m = time_ms / 1000.0 / 60.0;
-                let spectrum_data = &playing_channel.get_wave_data(self.num_spectrum_bars, 1).unwrap();
+                let spectrum_data = &self.backend.waveform(playing_channel, self.num_spectrum_bars);
                 let mut spectrum_tuples: Vec<(&str, u64)> = Vec::new();
                 for (idx, &s) in spectrum_data.iter().enumerate() { 
                     let value = (self.spectrum_data_last[idx].abs() + s.abs()) / 2.0 * 100.0 + 2.0;
@@ -150,26 +646,641 @@ impl MediaPlayer {
                     .data(&spectrum_tuples)
                     .max(100)
                     .render(f, player_chunks[0]);
+                let panel_title = format!("{}{} [{}]{}{}", self.playing_song_title.clone().unwrap_or_default(), if self.paused() { " PAUSED" } else { "" }, self.play_mode.label(),
+                    if self.rate() != 1.0 { format!(" {:.2}x", self.rate()) } else { String::new() },
+                    self.sleep_label().map(|label| format!(" sleep: {}", label)).unwrap_or_default());
                 Paragraph::new(info_text.iter())
-                    .block(Block::default().title(&format!("{}{}", list_member_titles[selected_idx], if false { " PAUSED" } else { "" })).borders(Borders::ALL))
+                    .block(Block::default().title(&panel_title).borders(Borders::ALL))
                     .alignment(Alignment::Left)
                     .render(f, player_chunks[1]);
+                let gauge_chunks = Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints(vec![Constraint::Percentage(75), Constraint::Percentage(25)])
+                    .split(player_chunks[2]);
                 Gauge::default()
                     .block(Block::default().borders(Borders::ALL))
                     .style(Style::default().fg(Color::White))
-                    .percent((time_ms / playing_song_handle.get_length(rfmod::TIMEUNIT_MS).unwrap() as f32 * 100.0) as u16)
-                    .label(&format!("{}{}:{}{}", if time_m < 10.0 { "0" } else { "" }, time_m as u32, if time_s < 10.0 { "0" } else { "" }, time_s as u32))
-                    .render(f, player_chunks[2]);
+                    .percent(cmp::min((time_ms / length_ms * 100.0) as u16, 100))
+                    .label(&match (&self.goto, self.resume_offer) {
+                        (Some(text), _) => format!("Go to (mm:ss): {}_", text),
+                        (None, Some(position)) => format!("Resume from {}? y/n", format_timestamp(position)),
+                        (None, None) => format!("{}{}:{}{}{}", if time_m < 10.0 { "0" } else { "" }, time_m as u32, if time_s < 10.0 { "0" } else { "" }, time_s as u32,
+                            match self.ab_loop {
+                                Some((a, Some(b))) => format!("  loop {}-{}", format_timestamp(a), format_timestamp(b)),
+                                Some((a, None)) => format!("  loop {}-", format_timestamp(a)),
+                                None => String::new()
+                            })
+                    })
+                    .render(f, gauge_chunks[0]);
+                let gauge_area = gauge_chunks[0];
+                if let (Some((a, b)), Some(length)) = (self.ab_loop, self.length()) {
+                    if gauge_area.width > 3 && gauge_area.height > 1 {
+                        let (column, marker) = loop_marker(gauge_area.width - 2, length, a, b);
+                        Paragraph::new([Text::styled(&marker, Style::default().fg(Color::Yellow))].iter())
+                            .render(f, Rect::new(gauge_area.x + 1 + column, gauge_area.y + gauge_area.height - 1, marker.chars().count() as u16, 1));
+                    }
+                }
+                let volume_percent = (self.state.volume * 100.0).round() as u16;
+                Gauge::default()
+                    .block(Block::default().borders(Borders::ALL))
+                    .style(Style::default().fg(if self.state.muted { Color::DarkGray } else { Color::White }))
+                    .percent(cmp::min(volume_percent, 100))
+                    .label(&if self.state.muted { String::from("Muted") } else { format!("Vol {}%", volume_percent) })
+                    .render(f, gauge_chunks[1]);
             }
         }
         self.last_song_title = self.playing_song_title.clone();
     }
 
-    fn input(&mut self, _key: Key, _fmod: &Sys) {
+    /// The level every channel plays at, before any crossfade. Includes the sleep timer's fade out.
+    pub fn gain(&self) -> f32 {
+        if self.state.muted { 0.0 } else { self.state.volume * self.sleep_fade() }
+    }
+
+    fn sleep_fade(&self) -> f32 {
+        match self.sleep {
+            Some(SleepTimer::After(_, deadline)) => {
+                let remaining = deadline.saturating_sub(self.backend.now_ms());
+                fade_gain(remaining as f32 / SLEEP_FADE_MS as f32)
+            }
+            _ => 1.0
+        }
+    }
+
+    // true when the sleep timer should stop playback instead of moving on to `next`
+    fn sleeps_before(&self, next: &Song) -> bool {
+        match (self.sleep, &self.now_playing) {
+            (Some(SleepTimer::EndOfTrack), _) => true,
+            (Some(SleepTimer::EndOfAlbum), Some(current)) => current.album.is_empty() || current.album != next.album,
+            _ => false
+        }
+    }
+
+    /// Steps through the sleep presets: 15 to 90 minutes, then the end of the track or album, then off.
+    pub fn cycle_sleep_timer(&mut self) {
+        let now = self.backend.now_ms();
+        let after = |idx: usize| SleepTimer::After(idx, now + SLEEP_PRESET_MINUTES[idx] * 60 * 1000);
+        self.sleep = match self.sleep {
+            None => Some(after(0)),
+            Some(SleepTimer::After(idx, _)) if idx + 1 < SLEEP_PRESET_MINUTES.len() => Some(after(idx + 1)),
+            Some(SleepTimer::After(_, _)) => Some(SleepTimer::EndOfTrack),
+            Some(SleepTimer::EndOfTrack) => Some(SleepTimer::EndOfAlbum),
+            Some(SleepTimer::EndOfAlbum) => None
+        };
+        // a next song that's already scheduled may be one the timer should stop before
+        self.unschedule_next();
+        self.update_levels();
+    }
+
+    pub fn cancel_sleep_timer(&mut self) {
+        self.sleep = None;
+        self.update_levels();
+    }
+
+    /// Countdown or end condition for the player panel, None when no timer is set.
+    pub fn sleep_label(&self) -> Option<String> {
+        match self.sleep? {
+            SleepTimer::After(_, deadline) => Some(format_timestamp(deadline.saturating_sub(self.backend.now_ms()) as u32)),
+            SleepTimer::EndOfTrack => Some(String::from("end of track")),
+            SleepTimer::EndOfAlbum => Some(String::from("end of album"))
+        }
+    }
+
+    fn update_sleep(&mut self) {
+        match self.sleep {
+            Some(SleepTimer::After(_, deadline)) if self.backend.now_ms() >= deadline => {
+                self.sleep = None;
+                self.pause();
+            }
+            Some(SleepTimer::EndOfTrack) | Some(SleepTimer::EndOfAlbum) => {
+                // a scheduled next song is one the timer lets through, promote_if_finished takes care of it
+                let ended = self.playing_channel.map_or(false, |c| !self.backend.is_playing(c));
+                let scheduled = self.next.as_ref().map_or(false, |next| next.channel.is_some());
+                if ended && !scheduled {
+                    self.sleep = None;
+                    self.held = true;
+                    self.drop_next();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.state.volume
+    }
+
+    pub fn muted(&self) -> bool {
+        self.state.muted
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.state.volume = volume.max(0.0).min(1.0);
+        self.state.muted = false;
+        self.volume_changed();
+    }
+
+    pub fn toggle_mute(&mut self) {
+        self.state.muted = !self.state.muted;
+        self.volume_changed();
+    }
+
+    fn volume_changed(&mut self) {
+        self.update_levels();
+        if let Some(next) = self.next.as_ref().filter(|next| next.fade_ms == 0) {
+            if let Some(channel) = next.channel { self.backend.set_volume(channel, self.level(Some(&next.song))); }
+        }
+        self.save_state();
+    }
+
+    fn save_state(&mut self) {
+        if let Err(e) = self.state.save() {
+            self.error = Some(format!("Couldn't save state: {}", e));
+        }
+    }
+
+    /// Playback speed of the current song, remembered per file.
+    pub fn rate(&self) -> f32 {
+        match &self.now_playing {
+            Some(song) => self.state.rate(&song.path),
+            None => 1.0
+        }
+    }
+
+    pub fn set_rate(&mut self, rate: f32) {
+        let path = match &self.now_playing {
+            Some(song) => song.path.clone(),
+            None => return
+        };
+        // rounded so repeated steps don't drift away from 1.0
+        let rate = (rate.max(MIN_RATE).min(MAX_RATE) * 100.0).round() / 100.0;
+        self.interrupt_transition();
+        if let Some(channel) = self.playing_channel { self.backend.set_rate(channel, rate); }
+        self.state.set_rate(&path, rate);
+        self.save_state();
+    }
+
+    /// True while the go to time prompt is open and every key should come here.
+    pub fn capturing_input(&self) -> bool {
+        self.goto.is_some()
+    }
+
+    fn goto_input(&mut self, key: Key) {
+        match key {
+            Key::Char('\n') => {
+                match self.goto.take().and_then(|text| parse_timestamp(&text)) {
+                    Some(position) => self.set_position(position),
+                    None => self.error = Some(String::from("Go to expects mm:ss"))
+                }
+            }
+            Key::Esc => self.goto = None,
+            Key::Backspace => { if let Some(text) = self.goto.as_mut() { text.pop(); } }
+            Key::Char(c) if c.is_ascii_digit() || c == ':' => { if let Some(text) = self.goto.as_mut() { text.push(c); } }
+            _ => {}
+        }
+    }
+
+    /// Keys that work the same on every tab. Returns true if the key was used.
+    pub fn input(&mut self, key: Key) -> bool {
+        if self.capturing_input() {
+            self.goto_input(key);
+            return true;
+        }
+        // any other key leaves the offer behind and goes on to do its usual job
+        if let Some(position) = self.resume_offer.take() {
+            match key {
+                Key::Char('y') => { self.set_position(position); return true; }
+                Key::Char('n') | Key::Esc => return true,
+                _ => {}
+            }
+        }
+        match key {
+            Key::Char('z') => { let step = self.seek_step_ms(); self.seek_backward(step); }
+            Key::Char('x') => { let step = self.seek_step_ms(); self.seek_forward(step); }
+            Key::Char('Z') => { let step = self.seek_step_large_ms(); self.seek_backward(step); }
+            Key::Char('X') => { let step = self.seek_step_large_ms(); self.seek_forward(step); }
+            Key::Char(c) if c.is_ascii_digit() => self.seek_to_percent(c.to_digit(10).unwrap_or(0) * 10),
+            Key::Char('g') if self.playing_channel.is_some() => self.goto = Some(String::new()),
+            Key::Char('+') | Key::Char('=') => {
+                let volume = self.state.volume + VOLUME_STEP;
+                self.set_volume(volume);
+            }
+            Key::Char('-') => {
+                let volume = self.state.volume - VOLUME_STEP;
+                self.set_volume(volume);
+            }
+            Key::Char('M') => self.toggle_mute(),
+            Key::Char('t') => self.cycle_sleep_timer(),
+            Key::Char('T') => self.cancel_sleep_timer(),
+            Key::Char('a') => self.mark_loop_point(),
+            Key::Char('<') => { let rate = self.rate() - RATE_STEP; self.set_rate(rate); }
+            Key::Char('>') => { let rate = self.rate() + RATE_STEP; self.set_rate(rate); }
+            _ => return false
+        }
+        true
+    }
+
+    // runs every tick regardless of the active tab, so queued songs keep playing from anywhere
+    pub fn tick(&mut self) {
+        self.backend.update();
+        self.update_sleep();
+        self.update_loop();
+        if let Some(position) = self.position() {
+            if !self.paused() && position.saturating_sub(self.resume_saved_at.unwrap_or(0)) >= RESUME_SAVE_INTERVAL_MS {
+                self.remember_position();
+            }
+        }
+        if self.wants_next() {
+            if let Some(song) = self.queue.pop_next() { self.prepare_next(song); }
+        }
+        self.schedule_next();
+        self.promote_if_finished();
+        self.update_levels();
+        if self.now_playing.is_some() && !self.queue.is_empty() && self.almost_over() {
+            if let Some(song) = self.queue.pop_next() {
+                self.play_song(song);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::audio::testing::{ song, media_player };
+    use super::super::replaygain::ReplayGainMode;
+
+    fn album_song(path: &str, album: &str) -> Song {
+        Song { album: String::from(album), length: 10000, ..song(path) }
+    }
+
+    fn volumes(media_player: &MediaPlayer) -> (f32, f32) {
+        let fading = media_player.fading_out.as_ref().map_or(0.0, |fade| media_player.backend.volume(fade.channel));
+        (fading, media_player.backend.volume(media_player.playing_channel.unwrap()))
+    }
 
+    #[test]
+    fn almost_over_near_end_of_track() {
+        let (mut media_player, clock) = media_player(Config::default(), &[("a.mp3", 60000)]);
+        assert!(media_player.almost_over());
+        media_player.play_song(song("a.mp3"));
+        assert!(!media_player.almost_over());
+        clock.advance(59000);
+        assert!(!media_player.almost_over());
+        clock.advance(1000);
+        assert!(media_player.almost_over());
     }
 
-    fn tick(&mut self, _fmod: &Sys) {
+    #[test]
+    fn seek_keys_step_and_clamp() {
+        let (mut media_player, clock) = media_player(Config::default(), &[("a.mp3", 120000)]);
+        media_player.play_song(song("a.mp3"));
+        clock.advance(30000);
+        media_player.input(Key::Char('z'));
+        assert_eq!(media_player.position(), Some(20000));
+        media_player.input(Key::Char('x'));
+        media_player.input(Key::Char('x'));
+        assert_eq!(media_player.position(), Some(40000));
+        media_player.input(Key::Char('X'));
+        assert_eq!(media_player.position(), Some(100000));
 
+        // seeking never leaves the track
+        media_player.input(Key::Char('X'));
+        assert_eq!(media_player.position(), Some(120000));
+        media_player.set_position(5000);
+        media_player.input(Key::Char('Z'));
+        assert_eq!(media_player.position(), Some(0));
+        media_player.set_position(500000);
+        assert_eq!(media_player.position(), Some(120000));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn configured_seek_steps_are_used() {
+        let config = Config { seek_step_ms: 5000, seek_step_large_ms: 30000, ..Config::default() };
+        let (mut media_player, _clock) = media_player(config, &[("a.mp3", 120000)]);
+        media_player.play_song(song("a.mp3"));
+        media_player.input(Key::Char('x'));
+        assert_eq!(media_player.position(), Some(5000));
+        media_player.input(Key::Char('X'));
+        assert_eq!(media_player.position(), Some(35000));
+    }
+
+    #[test]
+    fn number_keys_and_goto_prompt_jump() {
+        let (mut media_player, _clock) = media_player(Config::default(), &[("a.mp3", 200000)]);
+        media_player.play_song(song("a.mp3"));
+        media_player.input(Key::Char('3'));
+        assert_eq!(media_player.position(), Some(60000));
+        media_player.input(Key::Char('0'));
+        assert_eq!(media_player.position(), Some(0));
+
+        media_player.input(Key::Char('g'));
+        assert!(media_player.capturing_input());
+        for c in "2:0x5".chars() { media_player.input(Key::Char(c)); }
+        media_player.input(Key::Char('\n'));
+        assert!(!media_player.capturing_input());
+        assert_eq!(media_player.position(), Some(125000));
+
+        media_player.input(Key::Char('g'));
+        media_player.input(Key::Char('9'));
+        media_player.input(Key::Esc);
+        assert_eq!(media_player.position(), Some(125000));
+    }
+
+    #[test]
+    fn rate_keys_change_speed_per_file() {
+        let (mut media_player, clock) = media_player(Config::default(), &[("a.mp3", 600000), ("b.mp3", 600000)]);
+        media_player.play_song(song("a.mp3"));
+        for _ in 0..5 { media_player.input(Key::Char('>')); }
+        assert_eq!(media_player.rate(), 1.5);
+        clock.advance(10000);
+        assert_eq!(media_player.position(), Some(15000));
+        for _ in 0..20 { media_player.input(Key::Char('<')); }
+        assert_eq!(media_player.rate(), 0.5);
+
+        media_player.play_song(song("b.mp3"));
+        assert_eq!(media_player.rate(), 1.0);
+        media_player.play_song(song("a.mp3"));
+        assert_eq!(media_player.rate(), 0.5);
+        assert_eq!(media_player.backend.rate(media_player.playing_channel.unwrap()), 0.5);
+    }
+
+    #[test]
+    fn long_files_offer_to_resume() {
+        let (mut media_player, clock) = media_player(Config::default(), &[("book.mp3", 3600000), ("song.mp3", 200000)]);
+        media_player.play_song(song("book.mp3"));
+        assert_eq!(media_player.resume_offer(), None);
+        clock.advance(600000);
+        media_player.play_song(song("song.mp3"));
+        clock.advance(100000);
+        media_player.play_song(song("book.mp3"));
+        assert_eq!(media_player.resume_offer(), Some(600000));
+        assert!(media_player.input(Key::Char('y')));
+        assert_eq!(media_player.position(), Some(600000));
+
+        // short files are never remembered, and n starts over
+        media_player.play_song(song("song.mp3"));
+        assert_eq!(media_player.resume_offer(), None);
+        media_player.play_song(song("book.mp3"));
+        assert!(media_player.input(Key::Char('n')));
+        assert_eq!(media_player.resume_offer(), None);
+        assert_eq!(media_player.position(), Some(0));
+    }
+
+    #[test]
+    fn finished_long_files_are_forgotten() {
+        let config = Config { resume_threshold_secs: 60, ..Config::default() };
+        let (mut media_player, clock) = media_player(config, &[("a.mp3", 120000), ("b.mp3", 120000)]);
+        media_player.play_song(song("a.mp3"));
+        clock.advance(40000);
+        media_player.tick();
+        assert_eq!(media_player.state.resume_position("a.mp3"), Some(40000));
+        media_player.input(Key::Char('9'));
+        clock.advance(10000);
+        media_player.play_song(song("b.mp3"));
+        assert_eq!(media_player.state.resume_position("a.mp3"), None);
+    }
+
+    #[test]
+    fn sleep_timer_fades_out_then_pauses() {
+        let (mut media_player, clock) = media_player(Config::default(), &[("a.mp3", 3600000)]);
+        media_player.play_song(song("a.mp3"));
+        media_player.input(Key::Char('t'));
+        media_player.input(Key::Char('t'));
+        assert_eq!(media_player.sleep_label(), Some(String::from("30:00")));
+
+        clock.advance(30 * 60 * 1000 - 15000);
+        media_player.tick();
+        assert!((volumes(&media_player).1 - 0.707).abs() < 0.001);
+        clock.advance(15000);
+        media_player.tick();
+        assert!(media_player.paused());
+        assert_eq!(media_player.sleep_label(), None);
+        assert_eq!(volumes(&media_player).1, 1.0);
+    }
+
+    #[test]
+    fn sleep_at_end_of_track_holds_the_queue() {
+        let (mut media_player, clock) = media_player(Config::default(), &[("a.mp3", 10000), ("b.mp3", 10000)]);
+        media_player.play_song(song("a.mp3"));
+        media_player.queue.push(song("b.mp3"));
+        for _ in 0..SLEEP_PRESET_MINUTES.len() + 1 { media_player.input(Key::Char('t')); }
+        assert_eq!(media_player.sleep_label(), Some(String::from("end of track")));
+
+        clock.advance(10000);
+        media_player.tick();
+        assert_eq!(media_player.now_playing.as_ref().map(|s| s.path.as_str()), Some("a.mp3"));
+        assert!(!media_player.almost_over());
+        assert_eq!(media_player.queue.len(), 1);
+
+        media_player.toggle_pause();
+        media_player.tick();
+        assert_eq!(media_player.now_playing.as_ref().map(|s| s.path.as_str()), Some("b.mp3"));
+    }
+
+    #[test]
+    fn sleep_at_end_of_album_lets_the_album_finish() {
+        let (mut media_player, clock) = media_player(Config::default(), &[("a.mp3", 10000), ("b.mp3", 10000), ("c.mp3", 10000)]);
+        media_player.play_song(album_song("a.mp3", "A"));
+        for _ in 0..SLEEP_PRESET_MINUTES.len() + 2 { media_player.input(Key::Char('t')); }
+        assert_eq!(media_player.sleep_label(), Some(String::from("end of album")));
+
+        clock.advance(6000);
+        media_player.prepare_next(album_song("b.mp3", "A"));
+        clock.advance(4000);
+        media_player.tick();
+        assert_eq!(media_player.now_playing.as_ref().map(|s| s.path.as_str()), Some("b.mp3"));
+
+        clock.advance(6000);
+        media_player.prepare_next(album_song("c.mp3", "B"));
+        clock.advance(4000);
+        media_player.tick();
+        assert_eq!(media_player.now_playing.as_ref().map(|s| s.path.as_str()), Some("b.mp3"));
+        assert!(!media_player.has_prepared());
+        assert!(!media_player.almost_over());
+    }
+
+    #[test]
+    fn ab_loop_repeats_the_marked_range() {
+        let (mut media_player, clock) = media_player(Config::default(), &[("a.mp3", 60000), ("b.mp3", 60000)]);
+        media_player.queue.push(song("b.mp3"));
+        media_player.play_song(song("a.mp3"));
+        clock.advance(10000);
+        media_player.input(Key::Char('a'));
+        clock.advance(5000);
+        media_player.input(Key::Char('a'));
+        assert_eq!(media_player.ab_loop(), Some((10000, Some(15000))));
+        clock.advance(5000);
+        media_player.tick();
+        assert_eq!(media_player.position(), Some(10000));
+
+        // the loop holds the queue back even when B is at the very end
+        media_player.input(Key::Char('a'));
+        assert_eq!(media_player.ab_loop(), None);
+        media_player.input(Key::Char('a'));
+        media_player.set_position(60000);
+        media_player.input(Key::Char('a'));
+        assert_eq!(media_player.ab_loop(), Some((10000, Some(59750))));
+        media_player.tick();
+        assert_eq!(media_player.now_playing.as_ref().unwrap().path, "a.mp3");
+        assert_eq!(media_player.position(), Some(10000));
+
+        media_player.input(Key::Char('a'));
+        media_player.set_position(58000);
+        for _ in 0..3 { clock.advance(1000); media_player.tick(); }
+        assert_eq!(media_player.now_playing.as_ref().unwrap().path, "b.mp3");
+        assert_eq!(media_player.ab_loop(), None);
+    }
+
+    #[test]
+    fn loop_marker_spans_the_gauge() {
+        assert_eq!(loop_marker(10, 100000, 20000, None), (2, String::from("A")));
+        assert_eq!(loop_marker(10, 100000, 20000, Some(60000)), (2, String::from("A───B")));
+        assert_eq!(loop_marker(10, 100000, 99000, Some(99500)), (8, String::from("AB")));
+    }
+
+    #[test]
+    fn timestamps_parse() {
+        assert_eq!(parse_timestamp("90"), Some(90000));
+        assert_eq!(parse_timestamp("1:05"), Some(65000));
+        assert_eq!(parse_timestamp("1:00:00"), Some(3600000));
+        assert_eq!(parse_timestamp("1::0"), None);
+        assert_eq!(parse_timestamp("1:2:3:4"), None);
+        assert_eq!(parse_timestamp(""), None);
+        assert_eq!(format_timestamp(65000), "1:05");
+        assert_eq!(format_timestamp(3725000), "1:02:05");
+    }
+
+    #[test]
+    fn queue_plays_when_track_ends() {
+        let (mut media_player, clock) = media_player(Config::default(), &[("a.mp3", 1000), ("b.mp3", 1000)]);
+        media_player.play_song(song("a.mp3"));
+        media_player.queue.push(song("b.mp3"));
+        media_player.tick();
+        assert_eq!(media_player.now_playing.as_ref().unwrap().path, "a.mp3");
+        clock.advance(1000);
+        media_player.tick();
+        assert_eq!(media_player.now_playing.as_ref().unwrap().path, "b.mp3");
+        assert!(media_player.queue.is_empty());
+        assert_eq!(media_player.history.len(), 1);
+    }
+
+    #[test]
+    fn prepared_song_starts_when_current_ends() {
+        let (mut media_player, clock) = media_player(Config::default(), &[("a.mp3", 10000), ("b.mp3", 10000)]);
+        media_player.play_song(song("a.mp3"));
+        assert!(!media_player.wants_next());
+        clock.advance(6000);
+        assert!(media_player.wants_next());
+        assert!(media_player.prepare_next(song("b.mp3")));
+        media_player.tick();
+        assert!(!media_player.almost_over());
+
+        // the tick lands 75ms after the boundary, b has been playing since exactly 10s
+        clock.advance(4075);
+        media_player.tick();
+        assert_eq!(media_player.now_playing.as_ref().unwrap().path, "b.mp3");
+        assert_eq!(media_player.position(), Some(75));
+        assert!(!media_player.has_prepared());
+        assert_eq!(media_player.history.len(), 1);
+    }
+
+    #[test]
+    fn pausing_holds_the_prepared_song_back() {
+        let (mut media_player, clock) = media_player(Config::default(), &[("a.mp3", 10000), ("b.mp3", 10000)]);
+        media_player.play_song(song("a.mp3"));
+        clock.advance(8000);
+        media_player.prepare_next(song("b.mp3"));
+        media_player.tick();
+        media_player.toggle_pause();
+        clock.advance(5000);
+        media_player.tick();
+        assert_eq!(media_player.now_playing.as_ref().unwrap().path, "a.mp3");
+
+        media_player.toggle_pause();
+        media_player.tick();
+        clock.advance(2000);
+        media_player.tick();
+        assert_eq!(media_player.now_playing.as_ref().unwrap().path, "b.mp3");
+        assert_eq!(media_player.position(), Some(0));
+    }
+
+    #[test]
+    fn crossfade_overlaps_tracks_from_different_albums() {
+        let config = Config { crossfade_ms: 2000, ..Config::default() };
+        let (mut media_player, clock) = media_player(config, &[("a.mp3", 10000), ("b.mp3", 10000)]);
+        media_player.play_song(album_song("a.mp3", "first"));
+        clock.advance(5500);
+        assert!(media_player.wants_next());
+        media_player.prepare_next(album_song("b.mp3", "second"));
+
+        // b takes over 2s before a ends
+        clock.advance(2500);
+        media_player.tick();
+        assert_eq!(media_player.now_playing.as_ref().unwrap().path, "b.mp3");
+        assert_eq!(volumes(&media_player), (1.0, 0.0));
+
+        clock.advance(1000);
+        media_player.tick();
+        let (fading, playing) = volumes(&media_player);
+        assert!((fading - playing).abs() < 0.001);
+        assert!((fading * fading + playing * playing - 1.0).abs() < 0.001);
+
+        clock.advance(1000);
+        media_player.tick();
+        assert!(media_player.fading_out.is_none());
+        assert_eq!(volumes(&media_player), (0.0, 1.0));
+    }
+
+    #[test]
+    fn same_album_runs_gaplessly_without_crossfade() {
+        let config = Config { crossfade_ms: 2000, ..Config::default() };
+        let (mut media_player, clock) = media_player(config, &[("a.mp3", 10000), ("b.mp3", 10000)]);
+        media_player.play_song(album_song("a.mp3", "live"));
+        clock.advance(5500);
+        media_player.prepare_next(album_song("b.mp3", "live"));
+        clock.advance(2500);
+        media_player.tick();
+        assert_eq!(media_player.now_playing.as_ref().unwrap().path, "a.mp3");
+        clock.advance(2000);
+        media_player.tick();
+        assert_eq!(media_player.now_playing.as_ref().unwrap().path, "b.mp3");
+        assert_eq!(media_player.position(), Some(0));
+        assert!(media_player.fading_out.is_none());
+    }
+
+    #[test]
+    fn volume_keys_adjust_playing_channel() {
+        let (mut media_player, _clock) = media_player(Config::default(), &[("a.mp3", 10000)]);
+        media_player.play_song(song("a.mp3"));
+        let channel = media_player.playing_channel.unwrap();
+        assert!(media_player.input(Key::Char('-')));
+        assert!((media_player.backend.volume(channel) - 0.95).abs() < 0.001);
+
+        assert!(media_player.input(Key::Char('M')));
+        assert_eq!(media_player.backend.volume(channel), 0.0);
+        assert!(media_player.input(Key::Char('+')));
+        assert!(!media_player.muted());
+
+        media_player.input(Key::Char('+'));
+        media_player.input(Key::Char('='));
+        assert_eq!(media_player.volume(), 1.0);
+        assert!(!media_player.input(Key::Char('q')));
+    }
+
+    #[test]
+    fn replay_gain_scales_channel_volume() {
+        let config = Config { replay_gain: ReplayGainMode::Album, ..Config::default() };
+        let (mut media_player, _clock) = media_player(config, &[("a.mp3", 10000), ("b.mp3", 10000)]);
+        let gain = ReplayGain { track_gain: Some(-6.0), album_gain: Some(-3.0), ..ReplayGain::default() };
+        media_player.play_song(Song { replay_gain: gain, ..song("a.mp3") });
+        assert!((volumes(&media_player).1 - 0.708).abs() < 0.001);
+
+        // album gain missing, so fall back to track gain, which can't boost past full volume
+        let gain = ReplayGain { track_gain: Some(6.0), track_peak: Some(0.8), ..ReplayGain::default() };
+        media_player.play_song(Song { replay_gain: gain, ..song("b.mp3") });
+        assert!((volumes(&media_player).1 - 1.0).abs() < 0.001);
+        media_player.input(Key::Char('-'));
+        assert!((volumes(&media_player).1 - 0.95).abs() < 0.001);
+    }
+}
@@ -0,0 +1,151 @@
+use termion::event::Key;
+use tui::backend::Backend;
+use tui::widgets::{ Widget, Block, Borders, SelectableList };
+use tui::style::{ Color, Modifier, Style};
+use tui::layout::Rect;
+use tui::terminal::Frame;
+use super::player::{ Player, Song };
+use super::MediaPlayer;
+
+/// Songs waiting to be played, from any source. Whatever is at the front plays when the current track ends.
+pub struct Queue {
+    songs: Vec<Song>,
+}
+
+impl Queue {
+    pub fn new() -> Queue {
+        Queue { songs: Vec::new() }
+    }
+
+    pub fn push(&mut self, song: Song) {
+        self.songs.push(song);
+    }
+
+    pub fn insert_next(&mut self, song: Song) {
+        self.songs.insert(0, song);
+    }
+
+    pub fn pop_next(&mut self) -> Option<Song> {
+        if self.songs.is_empty() { None } else { Some(self.songs.remove(0)) }
+    }
+
+    pub fn remove(&mut self, idx: usize) -> Option<Song> {
+        if idx < self.songs.len() { Some(self.songs.remove(idx)) } else { None }
+    }
+
+    /// Swaps the song at `idx` with the one before it, returning its new index.
+    pub fn move_up(&mut self, idx: usize) -> usize {
+        if idx > 0 && idx < self.songs.len() {
+            self.songs.swap(idx, idx - 1);
+            idx - 1
+        } else {
+            idx
+        }
+    }
+
+    /// Swaps the song at `idx` with the one after it, returning its new index.
+    pub fn move_down(&mut self, idx: usize) -> usize {
+        if idx + 1 < self.songs.len() {
+            self.songs.swap(idx, idx + 1);
+            idx + 1
+        } else {
+            idx
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.songs.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.songs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.songs.is_empty()
+    }
+
+    pub fn songs(&self) -> &[Song] {
+        &self.songs
+    }
+}
+
+pub struct QueueView {
+    selected: Option<usize>,
+}
+
+impl QueueView {
+    pub fn new() -> QueueView {
+        QueueView { selected: None }
+    }
+
+    fn titles(media_player: &MediaPlayer) -> Vec<String> {
+        media_player.queue.songs().iter().map(|s| if s.artist.is_empty() { s.name.clone() } else { format!("{} - {}", s.artist, s.name) }).collect()
+    }
+}
+
+impl Player for QueueView {
+    fn input(&mut self, key: Key, media_player: &mut MediaPlayer) {
+        let len = media_player.queue.len();
+        match key {
+            Key::Down if len > 0 => {
+                self.selected = match self.selected { Some(selected) if selected + 1 < len => Some(selected + 1), _ => Some(0) };
+            }
+            Key::Up if len > 0 => {
+                self.selected = match self.selected { Some(selected) if selected > 0 && selected < len => Some(selected - 1), _ => Some(len - 1) };
+            }
+            Key::Char('[') => {
+                if let Some(selected) = self.selected { self.selected = Some(media_player.queue.move_up(selected)); }
+            }
+            Key::Char(']') => {
+                if let Some(selected) = self.selected { self.selected = Some(media_player.queue.move_down(selected)); }
+            }
+            Key::Char('r') | Key::Delete => {
+                if let Some(selected) = self.selected {
+                    media_player.queue.remove(selected);
+                    let len = media_player.queue.len();
+                    self.selected = if len == 0 { None } else if selected >= len { Some(len - 1) } else { Some(selected) };
+                }
+            }
+            Key::Char('c') => {
+                media_player.queue.clear();
+                self.selected = None;
+            }
+            Key::Char('\n') => {
+                if let Some(song) = self.selected.and_then(|selected| media_player.queue.remove(selected)) {
+                    media_player.play_song(song);
+                    let len = media_player.queue.len();
+                    self.selected = if len == 0 { None } else { self.selected.map(|s| if s >= len { len - 1 } else { s }) };
+                }
+            }
+            Key::Char(' ') => {
+                if media_player.now_playing.is_some() {
+                    media_player.toggle_pause();
+                } else if let Some(song) = media_player.queue.pop_next() {
+                    media_player.play_song(song);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, _media_player: &mut MediaPlayer) {
+    }
+
+    fn draw<B: Backend>(&mut self, f: &mut Frame<B>, chunk: Rect, media_player: &mut MediaPlayer) {
+        let titles = QueueView::titles(media_player);
+        let list_title = format!("Queue ({})", titles.len());
+        if media_player.now_playing.is_some() {
+            media_player.draw(f, chunk, &list_title, titles, self.selected.unwrap_or(0));
+        } else {
+            SelectableList::default()
+                .block(Block::default().borders(Borders::ALL).title(&list_title))
+                .items(&titles)
+                .select(self.selected)
+                .style(Style::default().fg(Color::White))
+                .highlight_style(Style::default().fg(Color::White).modifier(Modifier::BOLD))
+                .highlight_symbol(">")
+                .render(f, chunk);
+        }
+    }
+}
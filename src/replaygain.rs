@@ -0,0 +1,69 @@
+use super::order::PlayMode;
+
+/// Which of a file's gains to apply. `Auto` uses album gain unless songs are shuffled,
+/// since album gain only keeps its meaning when an album plays through in order.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplayGainMode {
+    Off,
+    Track,
+    Album,
+    Auto,
+}
+
+impl Default for ReplayGainMode {
+    fn default() -> ReplayGainMode {
+        ReplayGainMode::Off
+    }
+}
+
+impl ReplayGainMode {
+    /// None when gain shouldn't be applied at all, otherwise whether album values are preferred.
+    pub fn use_album(self, play_mode: PlayMode) -> Option<bool> {
+        match self {
+            ReplayGainMode::Off => None,
+            ReplayGainMode::Track => Some(false),
+            ReplayGainMode::Album => Some(true),
+            ReplayGainMode::Auto => Some(play_mode != PlayMode::Shuffle),
+        }
+    }
+}
+
+/// ReplayGain values from a file's tags, gains in dB and peaks as linear sample amplitude.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl ReplayGain {
+    /// Linear volume factor for this file. Falls back to the other gain when the preferred one
+    /// is missing and to 1.0 when the file has none, and never lets the peak go past full scale.
+    /// Capped at 1.0 since fmod channel volume can't amplify, so positive gains only undo cuts.
+    pub fn scale(&self, album: bool, preamp_db: f32) -> f32 {
+        let (gain, peak) = match (album, self.album_gain, self.track_gain) {
+            (true, Some(gain), _) => (gain, self.album_peak),
+            (_, _, Some(gain)) => (gain, self.track_peak),
+            (false, Some(gain), None) => (gain, self.album_peak),
+            _ => return 1.0
+        };
+        let scale = 10f32.powf((gain + preamp_db) / 20.0).min(1.0);
+        match peak {
+            Some(peak) if peak > 0.0 => scale.min(1.0 / peak),
+            _ => scale
+        }
+    }
+}
+
+/// Parses gain values like "-6.48 dB" or "+2.1dB".
+pub fn parse_gain(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let number = if value.to_lowercase().ends_with("db") { &value[..value.len() - 2] } else { value };
+    number.trim().trim_start_matches('+').parse().ok()
+}
+
+pub fn parse_peak(value: &str) -> Option<f32> {
+    value.trim().parse().ok().filter(|&peak: &f32| peak >= 0.0)
+}
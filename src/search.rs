@@ -0,0 +1,118 @@
+use termion::event::Key;
+
+/// Case insensitive subsequence match, so "bcld" finds "Bad Cold".
+pub fn fuzzy_match(query: &str, haystack: &str) -> bool {
+    let haystack = haystack.to_lowercase();
+    let mut chars = haystack.chars();
+    query.to_lowercase().chars().filter(|c| !c.is_whitespace()).all(|q| chars.any(|c| c == q))
+}
+
+/// `/` search shared by the list views. While editing, typed keys go to the query and the list
+/// is narrowed to fuzzy matches; Enter keeps the filter so `n`/`N` can step through it, Esc drops it.
+#[derive(Clone)]
+pub struct Search {
+    pub query: String,
+    editing: bool,
+    matches: Vec<usize>,
+}
+
+impl Search {
+    pub fn new() -> Search {
+        Search {
+            query: String::new(),
+            editing: false,
+            matches: Vec::new(),
+        }
+    }
+
+    pub fn editing(&self) -> bool {
+        self.editing
+    }
+
+    pub fn active(&self) -> bool {
+        self.editing || !self.query.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.query.clear();
+        self.editing = false;
+        self.matches.clear();
+    }
+
+    pub fn set_query(&mut self, query: &str, haystacks: &[String]) {
+        self.query = String::from(query);
+        self.update(haystacks);
+    }
+
+    /// Returns true if the key was consumed by the search.
+    pub fn input(&mut self, key: Key, haystacks: &[String]) -> bool {
+        if self.editing {
+            match key {
+                Key::Char('\n') => { self.editing = false; }
+                Key::Esc => self.clear(),
+                Key::Backspace => { self.query.pop(); }
+                Key::Char(c) => { self.query.push(c); }
+                _ => return false
+            }
+        } else {
+            match key {
+                Key::Char('/') => {
+                    self.query.clear();
+                    self.editing = true;
+                }
+                Key::Esc if self.active() => self.clear(),
+                _ => return false
+            }
+        }
+        self.update(haystacks);
+        true
+    }
+
+    pub fn update(&mut self, haystacks: &[String]) {
+        self.matches = (0..haystacks.len()).filter(|&i| fuzzy_match(&self.query, &haystacks[i])).collect();
+    }
+
+    pub fn matches(&self) -> &[usize] {
+        &self.matches
+    }
+
+    /// The match after (or before) `from`, wrapping around. With nothing selected yet the first match is picked.
+    pub fn step(&self, from: Option<usize>, forward: bool) -> Option<usize> {
+        if self.matches.is_empty() { return from; }
+        let from = match from {
+            Some(from) => from,
+            None => return Some(self.matches[0])
+        };
+        if forward {
+            Some(*self.matches.iter().find(|&&m| m > from).unwrap_or(&self.matches[0]))
+        } else {
+            Some(*self.matches.iter().rev().find(|&&m| m < from).unwrap_or(&self.matches[self.matches.len() - 1]))
+        }
+    }
+
+    /// Keeps a selection inside the filtered set, moving it to the first match if it fell out.
+    pub fn constrain(&self, selected: Option<usize>) -> Option<usize> {
+        match selected {
+            Some(idx) if self.matches.contains(&idx) => Some(idx),
+            _ => self.matches.first().cloned().or(selected)
+        }
+    }
+
+    /// The visible rows and the highlighted row once the filter is applied.
+    pub fn filter_view(&self, items: &[String], selected: Option<usize>) -> (Vec<String>, Option<usize>) {
+        if !self.active() {
+            return (items.to_vec(), selected);
+        }
+        let rows = self.matches.iter().filter_map(|&i| items.get(i).cloned()).collect();
+        let row = selected.and_then(|idx| self.matches.iter().position(|&m| m == idx));
+        (rows, row)
+    }
+
+    pub fn title(&self, title: &str) -> String {
+        if self.active() {
+            format!("{} /{}{} ({} matches)", title, self.query, if self.editing { "_" } else { "" }, self.matches.len())
+        } else {
+            String::from(title)
+        }
+    }
+}
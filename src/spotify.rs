@@ -1,4 +1,3 @@
-use rfmod::Sys;
 use termion::event::Key;
 use tui::terminal::Frame;
 use tui::layout::Rect;
@@ -43,13 +42,13 @@ impl Player for SpotifyPlayer {
         
     }
 
-    fn input(&mut self, key: Key, fmod: &Sys, media_player: &mut MediaPlayer) {
+    fn input(&mut self, key: Key, media_player: &mut MediaPlayer) {
         match key {
             _ => {}
         }
     }
 
-    fn tick(&mut self, fmod: &Sys, media_player: &mut MediaPlayer) {
+    fn tick(&mut self, media_player: &mut MediaPlayer) {
         
     }
 }
\ No newline at end of file
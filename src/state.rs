@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::fs::{ self, File };
+use std::io::{ BufReader, BufWriter };
+use std::path::PathBuf;
+use super::util;
+use super::player::Song;
+use super::order::PlayMode;
+
+fn default_volume() -> f32 { 1.0 }
+
+/// What was open and playing when muscli last quit, put back on the next start. Songs are kept
+/// whole rather than as paths so the queue survives files the library no longer lists.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Session {
+    #[serde(default)]
+    pub tab: usize,
+    #[serde(default)]
+    pub local_dir: String,
+    #[serde(default)]
+    pub selected_song: Option<String>,
+    #[serde(default)]
+    pub playing: Option<Song>,
+    #[serde(default)]
+    pub position: u32,
+    #[serde(default)]
+    pub queue: Vec<Song>,
+    #[serde(default)]
+    pub play_mode: PlayMode,
+    #[serde(default)]
+    pub station: Option<String>,
+}
+
+/// Player settings that should survive a restart, kept in state.json under the data directory.
+/// Every field has a serde default so older files keep loading as fields are added.
+#[derive(Serialize, Deserialize)]
+pub struct PersistentState {
+    #[serde(default = "default_volume")]
+    pub volume: f32,
+    #[serde(default)]
+    pub muted: bool,
+    // playback rate by file, only for files that aren't at normal speed
+    #[serde(default)]
+    rates: HashMap<String, f32>,
+    // where long files were left off, in milliseconds
+    #[serde(default)]
+    positions: HashMap<String, u32>,
+    #[serde(default)]
+    pub session: Option<Session>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl PersistentState {
+    /// Defaults that are never written to disk.
+    pub fn new() -> PersistentState {
+        PersistentState {
+            volume: default_volume(),
+            muted: false,
+            rates: HashMap::new(),
+            positions: HashMap::new(),
+            session: None,
+            path: None,
+        }
+    }
+
+    /// A missing or unreadable file just means starting from the defaults.
+    pub fn load() -> PersistentState {
+        let path = util::data_file("state.json");
+        let state: Option<PersistentState> = path.as_ref()
+            .and_then(|p| File::open(p).ok())
+            .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok());
+        let mut state = state.unwrap_or_else(PersistentState::new);
+        state.path = path;
+        state
+    }
+
+    pub fn rate(&self, path: &str) -> f32 {
+        self.rates.get(path).cloned().unwrap_or(1.0)
+    }
+
+    pub fn set_rate(&mut self, path: &str, rate: f32) {
+        if rate == 1.0 {
+            self.rates.remove(path);
+        } else {
+            self.rates.insert(String::from(path), rate);
+        }
+    }
+
+    pub fn resume_position(&self, path: &str) -> Option<u32> {
+        self.positions.get(path).cloned()
+    }
+
+    pub fn set_resume_position(&mut self, path: &str, position: Option<u32>) {
+        match position {
+            Some(position) => { self.positions.insert(String::from(path), position); }
+            None => { self.positions.remove(path); }
+        }
+    }
+
+    pub fn save(&self) -> Result<(), failure::Error> {
+        let path = match self.path.as_ref() {
+            Some(p) => p,
+            None => return Ok(())
+        };
+        let tmp_path = path.with_extension("json.tmp");
+        serde_json::to_writer(BufWriter::new(File::create(&tmp_path)?), self)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
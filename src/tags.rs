@@ -0,0 +1,364 @@
+use std::fs::{ self, File };
+use std::convert::TryFrom;
+use std::io::{ Read, Seek, SeekFrom, Write };
+use std::path::Path;
+use id3::{ Tag, Content, Version };
+use super::replaygain::{ self, ReplayGain };
+
+// only the tail of an ogg file is read to find the final granule position
+const OGG_TAIL_BYTES: u64 = 65536;
+
+const REPLAYGAIN_KEYS: [&str; 4] = ["REPLAYGAIN_TRACK_GAIN", "REPLAYGAIN_TRACK_PEAK", "REPLAYGAIN_ALBUM_GAIN", "REPLAYGAIN_ALBUM_PEAK"];
+
+/// Whatever metadata a file's tags carried. Fields the container didn't have are left as `None`.
+#[derive(Debug, Default)]
+pub struct TrackTags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<u32>,
+    pub track: Option<u32>,
+    pub length: Option<u32>,
+    pub replay_gain: ReplayGain,
+}
+
+/// Reads tags from ID3v2 (mp3), FLAC and Ogg Vorbis files. The container is
+/// sniffed from the first bytes rather than trusted from the extension.
+pub fn read_tags(path: &Path) -> Result<TrackTags, failure::Error> {
+    let mut file = File::open(path)?;
+    let mut magic = [0u8; 8];
+    file.read_exact(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    if &magic[0..3] == b"ID3" {
+        read_id3(path)
+    } else if &magic[0..4] == b"fLaC" {
+        read_flac(&mut file)
+    } else if &magic[0..4] == b"OggS" {
+        read_ogg(&mut file)
+    } else {
+        Ok(TrackTags::default())
+    }
+}
+
+fn read_id3(path: &Path) -> Result<TrackTags, failure::Error> {
+    let tag = Tag::read_from_path(path)?;
+    let mut tags = TrackTags {
+        title: tag.title().map(String::from),
+        artist: tag.artist().map(String::from),
+        album: tag.album().map(String::from),
+        genre: tag.genre().map(String::from),
+        year: tag.year().map(|y| y as u32),
+        track: tag.track(),
+        length: tag.duration(),
+        replay_gain: ReplayGain::default(),
+    };
+    // replaygain lives in TXXX frames named like the vorbis comments
+    for frame in tag.frames() {
+        if let Content::ExtendedText(text) = frame.content() {
+            if text.description.to_uppercase().starts_with("REPLAYGAIN_") {
+                apply_comment(&mut tags, &text.description, &text.value);
+            }
+        }
+    }
+    Ok(tags)
+}
+
+/// Writes gain into a file's tags as REPLAYGAIN_* values, replacing any already there. Only mp3
+/// (ID3v2 TXXX frames) and FLAC (Vorbis comments) are supported.
+pub fn write_replay_gain(path: &Path, replay_gain: &ReplayGain) -> Result<(), failure::Error> {
+    let values = [
+        replay_gain.track_gain.map(|g| format!("{:.2} dB", g)),
+        replay_gain.track_peak.map(|p| format!("{:.6}", p)),
+        replay_gain.album_gain.map(|g| format!("{:.2} dB", g)),
+        replay_gain.album_peak.map(|p| format!("{:.6}", p)),
+    ];
+    let values: Vec<(&str, String)> = REPLAYGAIN_KEYS.iter().zip(values.iter())
+        .filter_map(|(&key, value)| value.clone().map(|v| (key, v)))
+        .collect();
+
+    let mut magic = [0u8; 4];
+    File::open(path)?.read_exact(&mut magic)?;
+    if &magic == b"fLaC" {
+        write_flac_comments(path, &values)
+    } else if &magic[0..3] == b"ID3" || path.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("mp3")) {
+        let mut tag = Tag::read_from_path(path).unwrap_or_else(|_| Tag::new());
+        for key in REPLAYGAIN_KEYS.iter() {
+            tag.remove_extended_text(Some(key), None);
+        }
+        for (key, value) in values {
+            tag.add_extended_text(key, value);
+        }
+        tag.write_to_path(path, Version::Id3v24)?;
+        Ok(())
+    } else {
+        Err(failure::err_msg("writing tags is only supported for mp3 and flac"))
+    }
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 | bytes[3] as u32
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    (bytes[3] as u32) << 24 | (bytes[2] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[0] as u32
+}
+
+fn le_u64(bytes: &[u8]) -> u64 {
+    (le_u32(&bytes[4..8]) as u64) << 32 | le_u32(&bytes[0..4]) as u64
+}
+
+// leading digits only, so "2004-05-01" and "3/12" both parse
+fn leading_number(value: &str) -> Option<u32> {
+    let digits: String = value.chars().take_while(|c| c.is_ascii_digit()).collect();
+    digits.parse().ok()
+}
+
+fn apply_comment(tags: &mut TrackTags, key: &str, value: &str) {
+    let value = String::from(value.trim());
+    if value.is_empty() { return; }
+    match key.to_uppercase().as_str() {
+        "TITLE" => tags.title = Some(value),
+        "ARTIST" => tags.artist = Some(value),
+        "ALBUM" => tags.album = Some(value),
+        "GENRE" => tags.genre = Some(value),
+        "DATE" | "YEAR" => tags.year = leading_number(&value),
+        "TRACKNUMBER" => tags.track = leading_number(&value),
+        "REPLAYGAIN_TRACK_GAIN" => tags.replay_gain.track_gain = replaygain::parse_gain(&value),
+        "REPLAYGAIN_TRACK_PEAK" => tags.replay_gain.track_peak = replaygain::parse_peak(&value),
+        "REPLAYGAIN_ALBUM_GAIN" => tags.replay_gain.album_gain = replaygain::parse_gain(&value),
+        "REPLAYGAIN_ALBUM_PEAK" => tags.replay_gain.album_peak = replaygain::parse_peak(&value),
+        _ => {}
+    }
+}
+
+// vorbis comment list as used by flac and ogg vorbis: little endian lengths, KEY=value pairs
+fn parse_vorbis_comments(data: &[u8], tags: &mut TrackTags) {
+    let comments = match split_vorbis_comments(data) {
+        Some((_, comments)) => comments,
+        None => return
+    };
+    for comment in comments {
+        if let Some(split) = comment.find('=') {
+            apply_comment(tags, &comment[..split], &comment[split + 1..]);
+        }
+    }
+}
+
+fn vorbis_comment_block(vendor: &[u8], comments: &[String]) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    data.extend_from_slice(vendor);
+    data.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        data.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        data.extend_from_slice(comment.as_bytes());
+    }
+    data
+}
+
+// (vendor, comments) from a vorbis comment list, as raw KEY=value strings
+fn split_vorbis_comments(data: &[u8]) -> Option<(Vec<u8>, Vec<String>)> {
+    if data.len() < 4 { return None; }
+    let vendor_len = le_u32(&data[0..4]) as usize;
+    if data.len() < 8 + vendor_len { return None; }
+    let vendor = data[4..4 + vendor_len].to_vec();
+    let mut pos = 4 + vendor_len;
+    let count = le_u32(&data[pos..pos + 4]);
+    pos += 4;
+    let mut comments = Vec::new();
+    for _ in 0..count {
+        if data.len() < pos + 4 { return None; }
+        let len = le_u32(&data[pos..pos + 4]) as usize;
+        pos += 4;
+        if data.len() < pos + len { return None; }
+        comments.push(String::from_utf8_lossy(&data[pos..pos + len]).into_owned());
+        pos += len;
+    }
+    Some((vendor, comments))
+}
+
+// the whole file is rewritten beside the original and renamed over it, so a failure can't truncate the audio
+fn write_flac_comments(path: &Path, values: &[(&str, String)]) -> Result<(), failure::Error> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+
+    let mut blocks: Vec<(u8, Vec<u8>)> = Vec::new();
+    let mut pos = 4;
+    loop {
+        if bytes.len() < pos + 4 { return Err(failure::err_msg("truncated flac metadata")); }
+        let last = bytes[pos] & 0x80 != 0;
+        let kind = bytes[pos] & 0x7f;
+        let len = be_u32(&[0, bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]]) as usize;
+        pos += 4;
+        if bytes.len() < pos + len { return Err(failure::err_msg("truncated flac metadata")); }
+        blocks.push((kind, bytes[pos..pos + len].to_vec()));
+        pos += len;
+        if last { break; }
+    }
+
+    let (vendor, mut comments) = match blocks.iter().find(|(kind, _)| *kind == 4) {
+        Some((_, data)) => split_vorbis_comments(data).ok_or_else(|| failure::err_msg("broken vorbis comments"))?,
+        None => (b"muscli".to_vec(), Vec::new())
+    };
+    comments.retain(|comment| {
+        let key = comment.split('=').next().unwrap_or("").to_uppercase();
+        !REPLAYGAIN_KEYS.contains(&key.as_str())
+    });
+    comments.extend(values.iter().map(|(key, value)| format!("{}={}", key, value)));
+    let block = vorbis_comment_block(&vendor, &comments);
+    match blocks.iter().position(|(kind, _)| *kind == 4) {
+        Some(idx) => blocks[idx].1 = block,
+        None => blocks.insert(1, (4, block))
+    }
+
+    let tmp_path = path.with_extension("flac.tmp");
+    {
+        let mut out = File::create(&tmp_path)?;
+        out.write_all(b"fLaC")?;
+        let count = blocks.len();
+        for (i, (kind, data)) in blocks.iter().enumerate() {
+            let flag = if i + 1 == count { 0x80 } else { 0 };
+            let len = data.len() as u32;
+            out.write_all(&[flag | kind, (len >> 16) as u8, (len >> 8) as u8, len as u8])?;
+            out.write_all(data)?;
+        }
+        out.write_all(&bytes[pos..])?;
+    }
+    fs::set_permissions(&tmp_path, fs::metadata(path)?.permissions())?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn read_flac(file: &mut File) -> Result<TrackTags, failure::Error> {
+    let mut tags = TrackTags::default();
+    file.seek(SeekFrom::Start(4))?;
+
+    loop {
+        let mut header = [0u8; 4];
+        file.read_exact(&mut header)?;
+        let last = header[0] & 0x80 != 0;
+        let len = be_u32(&[0, header[1], header[2], header[3]]) as usize;
+        match header[0] & 0x7f {
+            0 => { // STREAMINFO
+                let mut data = vec![0u8; len];
+                file.read_exact(&mut data)?;
+                if data.len() >= 18 {
+                    let sample_rate = (data[10] as u64) << 12 | (data[11] as u64) << 4 | (data[12] as u64) >> 4;
+                    let total_samples = ((data[13] & 0x0f) as u64) << 32 | be_u32(&data[14..18]) as u64;
+                    if sample_rate > 0 && total_samples > 0 {
+                        tags.length = Some((total_samples * 1000 / sample_rate) as u32);
+                    }
+                }
+            }
+            4 => { // VORBIS_COMMENT
+                let mut data = vec![0u8; len];
+                file.read_exact(&mut data)?;
+                parse_vorbis_comments(&data, &mut tags);
+            }
+            _ => { file.seek(SeekFrom::Current(len as i64))?; }
+        }
+        if last { break; }
+    }
+
+    Ok(tags)
+}
+
+// reassembles the first `count` packets of the (first) logical stream
+fn read_ogg_packets(file: &mut File, count: usize) -> Result<Vec<Vec<u8>>, failure::Error> {
+    let mut packets = Vec::new();
+    let mut current = Vec::new();
+
+    while packets.len() < count {
+        let mut header = [0u8; 27];
+        file.read_exact(&mut header)?;
+        if &header[0..4] != b"OggS" {
+            return Err(failure::err_msg("broken ogg page"));
+        }
+        let mut lacing = vec![0u8; header[26] as usize];
+        file.read_exact(&mut lacing)?;
+        for &segment_len in &lacing {
+            let mut segment = vec![0u8; segment_len as usize];
+            file.read_exact(&mut segment)?;
+            current.extend_from_slice(&segment);
+            if segment_len < 255 {
+                packets.push(current);
+                current = Vec::new();
+                if packets.len() == count { break; }
+            }
+        }
+    }
+
+    Ok(packets)
+}
+
+fn last_ogg_granule(file: &mut File) -> Result<Option<u64>, failure::Error> {
+    let file_len = file.seek(SeekFrom::End(0))?;
+    let start = if file_len > OGG_TAIL_BYTES { file_len - OGG_TAIL_BYTES } else { 0 };
+    file.seek(SeekFrom::Start(start))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+
+    if tail.len() < 14 { return Ok(None); }
+    let mut idx = tail.len() - 14;
+    loop {
+        if &tail[idx..idx + 4] == b"OggS" {
+            return Ok(Some(le_u64(&tail[idx + 6..idx + 14])));
+        }
+        if idx == 0 { break; }
+        idx -= 1;
+    }
+    Ok(None)
+}
+
+fn read_ogg(file: &mut File) -> Result<TrackTags, failure::Error> {
+    let mut tags = TrackTags::default();
+    let packets = read_ogg_packets(file, 2)?;
+    let (ident, comments) = (&packets[0], &packets[1]);
+    let granule = last_ogg_granule(file)?;
+
+    if ident.len() >= 16 && &ident[0..7] == b"\x01vorbis" && comments.starts_with(b"\x03vorbis") {
+        parse_vorbis_comments(&comments[7..], &mut tags);
+        let sample_rate = le_u32(&ident[12..16]) as u64;
+        // the granule comes straight from the file, so a broken one is left as an unknown length
+        if let (Some(granule), true) = (granule, sample_rate > 0) {
+            tags.length = granule.checked_mul(1000).and_then(|ms| u32::try_from(ms / sample_rate).ok());
+        }
+    }
+
+    Ok(tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+    use super::*;
+
+    // an ogg page holding one whole packet
+    fn page(granule: u64, packet: &[u8]) -> Vec<u8> {
+        let mut page = Vec::from(&b"OggS\0\0"[..]);
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&[0; 12]);
+        page.push(1);
+        page.push(packet.len() as u8);
+        page.extend_from_slice(packet);
+        page
+    }
+
+    #[test]
+    fn huge_ogg_granule_leaves_length_unknown() {
+        let path = std::env::temp_dir().join(format!("muscli-tags-test-{}.ogg", process::id()));
+        let mut ident = Vec::from(&b"\x01vorbis\0\0\0\0\x02"[..]);
+        ident.extend_from_slice(&44100u32.to_le_bytes());
+        ident.extend_from_slice(&[0; 14]);
+        let mut bytes = page(0, &ident);
+        bytes.extend(page(0, b"\x03vorbis\0\0\0\0\0\0\0\0"));
+        bytes.extend(page(u64::max_value(), &[]));
+        fs::write(&path, &bytes).unwrap();
+        let tags = read_tags(&path);
+        let _ = fs::remove_file(&path);
+        assert_eq!(tags.unwrap().length, None);
+    }
+}
@@ -1,3 +1,6 @@
+use std::fs;
+use std::path::PathBuf;
+
 pub struct TabsState<'a> {
     pub titles: Vec<&'a str>,
     pub index: usize,
@@ -19,3 +22,13 @@ impl<'a> TabsState<'a> {
         }
     }
 }
+
+
+/// Path of `name` inside muscli's data directory, creating the directory if needed.
+pub fn data_file(name: &str) -> Option<PathBuf> {
+    let mut path = dirs::data_dir()?;
+    path.push("muscli");
+    fs::create_dir_all(&path).ok()?;
+    path.push(name);
+    Some(path)
+}